@@ -0,0 +1,68 @@
+use cogito::{AllocRecorder, Instrumented};
+use std::alloc::System;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+#[global_allocator]
+static ALLOC: AllocRecorder<System> = AllocRecorder::new(System);
+
+type Task = Pin<Box<dyn Future<Output = ()>>>;
+
+/// Polls the tasks in turn until all of them are ready, so their polls interleave on one thread.
+fn run(tasks: Vec<Task>) {
+    let mut cx = Context::from_waker(Waker::noop());
+
+    let mut queue: VecDeque<Task> = tasks.into_iter().collect();
+    while let Some(mut task) = queue.pop_front() {
+        if task.as_mut().poll(&mut cx).is_pending() {
+            queue.push_back(task);
+        }
+    }
+}
+
+/// Gives the executor back once, like an await on IO.
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<()> {
+        if self.0 {
+            Poll::Ready(())
+        } else {
+            self.0 = true;
+            Poll::Pending
+        }
+    }
+}
+
+async fn buffer(cache: Arc<Mutex<Vec<Vec<u8>>>>, size: usize, count: usize) {
+    for _ in 0..count {
+        cache.lock().unwrap().push(vec![0; size]);
+        YieldNow(false).await;
+    }
+}
+
+fn main() {
+    ALLOC.init_collector().unwrap();
+
+    let cache = Arc::new(Mutex::new(Vec::new()));
+    run(vec![
+        Box::pin(Instrumented::new(buffer(cache.clone(), 1024, 10), "task:small")),
+        Box::pin(Instrumented::new(buffer(cache.clone(), 4096, 10), "task:large")),
+    ]);
+
+    {
+        let report = ALLOC.report().unwrap();
+        for (task, stats) in report.as_ref().tag_stats() {
+            println!("{}: {}", task, stats);
+        }
+    }
+
+    drop(cache);
+
+    ALLOC.pause();
+}
@@ -1,13 +1,12 @@
-use cogito::AllocRecorder;
+use cogito::{AllocRecorder, Metric};
 use std::alloc::System;
 use std::fs::File;
-use std::sync::atomic::Ordering;
 
 #[global_allocator]
 static ALLOC: AllocRecorder<System> = AllocRecorder::new(System);
 
 fn quick_sort(input: Vec<u32>) -> Vec<u32> {
-    if input.len() == 0 {
+    if input.is_empty() {
         return Vec::new();
     }
 
@@ -16,18 +15,18 @@ fn quick_sort(input: Vec<u32>) -> Vec<u32> {
         let left: Vec<u32> = input
             .iter()
             .filter(|item| **item < mid)
-            .map(|item| item.clone())
+            .copied()
             .collect();
         let right: Vec<u32> = input
             .iter()
             .filter(|item| **item > mid)
-            .map(|item| item.clone())
+            .copied()
             .collect();
 
         quick_sort(left)
             .into_iter()
-            .chain(vec![mid].into_iter())
-            .chain((quick_sort(right)).into_iter())
+            .chain(vec![mid])
+            .chain(quick_sort(right))
             .collect()
     } else {
         vec![mid]
@@ -35,7 +34,7 @@ fn quick_sort(input: Vec<u32>) -> Vec<u32> {
 }
 
 fn main() {
-    ALLOC.init_collector();
+    ALLOC.init_collector().unwrap();
 
     let mut vec = Vec::new();
 
@@ -44,25 +43,24 @@ fn main() {
     }
 
     let mut thread_handlers = Vec::new();
-    for _ in 0..100 {
+    for index in 0..100 {
         let vec = vec.clone();
-        thread_handlers.push(std::thread::spawn(move || {
+        let builder = std::thread::Builder::new().name(format!("sort-{}", index % 4));
+        thread_handlers.push(builder.spawn(move || {
             let _sorted = quick_sort(vec);
-        }));
+        }).unwrap());
     }
 
     for thread in thread_handlers {
         thread.join().unwrap();
     }
 
-    let report = ALLOC.report();
+    let report = ALLOC.report().unwrap();
 
     let file = File::create("flamegraph.svg").unwrap();
-    report.as_ref().flamegraph(file);
+    report.as_ref().flamegraph_by_thread(Metric::LiveBytes, file).unwrap();
 
     println!("report: {}", report.as_ref());
 
-    cogito::PROFILE.with(|profile| {
-        profile.store(false, Ordering::SeqCst);
-    })
+    ALLOC.pause();
 }
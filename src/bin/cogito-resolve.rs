@@ -0,0 +1,51 @@
+//! Resolves a raw report written by `AllocRecorder::write_raw_report`, and prints it.
+//!
+//! Usage: cogito-resolve <raw report> [<module>=<debug file>]... [--flamegraph <svg>]
+
+use cogito::{Metric, RawReport, Resolver};
+use std::fs::File;
+use std::io::BufReader;
+use std::process::exit;
+
+fn usage() -> ! {
+    eprintln!("usage: cogito-resolve <raw report> [<module>=<debug file>]... [--flamegraph <svg>]");
+    exit(2)
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let path = args.next().unwrap_or_else(|| usage());
+
+    let mut resolver = Resolver::new();
+    let mut flamegraph = None;
+    while let Some(arg) = args.next() {
+        if arg == "--flamegraph" {
+            flamegraph = Some(args.next().unwrap_or_else(|| usage()));
+        } else if let Some(index) = arg.find('=') {
+            resolver = resolver.debug_file(&arg[..index], &arg[index + 1..]);
+        } else {
+            usage();
+        }
+    }
+
+    let raw = File::open(&path)
+        .and_then(|file| RawReport::read(BufReader::new(file)))
+        .unwrap_or_else(|err| {
+            eprintln!("failed to read {}: {}", path, err);
+            exit(1)
+        });
+    let report = resolver.resolve(&raw);
+
+    match flamegraph {
+        Some(svg) => {
+            let result = File::create(&svg)
+                .map_err(cogito::Error::from)
+                .and_then(|file| report.flamegraph(Metric::LiveBytes, file));
+            if let Err(err) = result {
+                eprintln!("failed to write {}: {}", svg, err);
+                exit(1)
+            }
+        }
+        None => print!("{}", report),
+    }
+}
@@ -1,53 +1,443 @@
-use std::sync::Arc;
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
 
 use crossbeam::queue::{ArrayQueue, PushError};
+use crossbeam::sync::Unparker;
 use crossbeam::utils::Backoff;
 
+/// Number of events a ring could buffer before its producer has to wait for the collector.
+pub const RING_CAPACITY: usize = 64;
+
+/// Sequence number of an event which is not in flight.
+const IDLE: u64 = u64::MAX;
+
+/// A slot of a ring, holding an event and its sequence number.
+type Slot<T> = UnsafeCell<MaybeUninit<(u64, T)>>;
+
+/// Ring is the event buffer of a thread. Events are written by the owning thread and read by the
+/// collector thread, without any allocation. Every event carries a global sequence number, so the
+/// collector can merge the rings of all threads back into the order in which the events happened.
+pub struct Ring<T> {
+    slots: Box<[Slot<T>]>,
+
+    /// Next slot to read, only written by the collector.
+    head: AtomicUsize,
+
+    /// Next slot to write, only written by the producer.
+    tail: AtomicUsize,
+
+    /// A lower bound of the sequence number of the event being written, or `IDLE`.
+    inflight: AtomicU64,
+
+    /// Serializes producers. A ring has a single producer most of the time, but a thread which is
+    /// exiting could still write into the ring it has just given back.
+    lock: AtomicBool,
+
+    /// Whether the ring is given to a thread.
+    owned: AtomicBool,
+
+    next: *mut Ring<T>,
+}
+
+unsafe impl<T: Send> Send for Ring<T> {}
+unsafe impl<T: Send> Sync for Ring<T> {}
+
+impl<T> Ring<T> {
+    fn new() -> Self {
+        Ring {
+            slots: (0..RING_CAPACITY)
+                .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+                .collect(),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            inflight: AtomicU64::new(IDLE),
+            lock: AtomicBool::new(false),
+            owned: AtomicBool::new(true),
+            next: null_mut(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tail.load(Ordering::SeqCst) - self.head.load(Ordering::SeqCst)
+    }
+}
+
+/// Registry owns the rings of all threads. Rings are never freed: a ring given back by an exiting
+/// thread is reused by the next new thread.
+pub struct Registry<T> {
+    rings: AtomicPtr<Ring<T>>,
+    sequence: AtomicU64,
+    collector: AtomicPtr<Unparker>,
+}
+
+/// Sending is split into `begin` and `commit`, so the sequence number could be taken at the moment
+/// the operation happens, e.g. before the memory is given back to the inner allocator.
+pub struct Ticket<T: 'static> {
+    ring: &'static Ring<T>,
+    seq: u64,
+}
+
+impl<T: 'static> Registry<T> {
+    pub const fn new() -> Self {
+        Registry {
+            rings: AtomicPtr::new(null_mut()),
+            sequence: AtomicU64::new(0),
+            collector: AtomicPtr::new(null_mut()),
+        }
+    }
+
+    /// Sets the collector thread woken up when a ring is filling up.
+    pub fn set_collector(&self, unparker: Unparker) {
+        let unparker = Box::leak(Box::new(unparker));
+        self.collector.store(unparker, Ordering::SeqCst);
+    }
+
+    pub fn wake_collector(&self) {
+        let unparker = self.collector.load(Ordering::SeqCst);
+        if !unparker.is_null() {
+            unsafe { (*unparker).unpark() }
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &'static Ring<T>> {
+        let mut ring = self.rings.load(Ordering::SeqCst);
+        std::iter::from_fn(move || {
+            if ring.is_null() {
+                None
+            } else {
+                let current = unsafe { &*ring };
+                ring = current.next;
+                Some(current)
+            }
+        })
+    }
+
+    /// Gives a ring to the current thread. It allocates when no ring could be reused, so the caller
+    /// must make sure the allocation is not recorded.
+    pub fn acquire(&self) -> &'static Ring<T> {
+        for ring in self.iter() {
+            if ring
+                .owned
+                .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+            {
+                return ring;
+            }
+        }
+
+        let ring = Box::leak(Box::new(Ring::new()));
+        let mut head = self.rings.load(Ordering::SeqCst);
+        loop {
+            ring.next = head;
+            match self
+                .rings
+                .compare_exchange(head, ring, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => return ring,
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    pub fn release(&self, ring: &Ring<T>) {
+        ring.owned.store(false, Ordering::SeqCst);
+    }
+
+    /// The sequence number of the next event.
+    pub fn sequence(&self) -> u64 {
+        self.sequence.load(Ordering::SeqCst)
+    }
+
+    pub fn begin(&self, ring: &'static Ring<T>) -> Ticket<T> {
+        let backoff = Backoff::new();
+        while ring
+            .lock
+            .compare_exchange_weak(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            backoff.snooze();
+        }
+
+        // publish a lower bound before taking the number, so the collector never misses it
+        ring.inflight.store(self.sequence.load(Ordering::SeqCst), Ordering::SeqCst);
+        let seq = self.sequence.fetch_add(1, Ordering::SeqCst);
+
+        Ticket { ring, seq }
+    }
+
+    pub fn commit(&self, ticket: Ticket<T>, item: T) {
+        let ring = ticket.ring;
+
+        let tail = ring.tail.load(Ordering::SeqCst);
+        let backoff = Backoff::new();
+        while tail - ring.head.load(Ordering::SeqCst) >= RING_CAPACITY {
+            self.wake_collector();
+            backoff.snooze();
+        }
+
+        unsafe {
+            (*ring.slots[tail % RING_CAPACITY].get())
+                .as_mut_ptr()
+                .write((ticket.seq, item));
+        }
+        ring.tail.store(tail + 1, Ordering::SeqCst);
+
+        self.finish(ring);
+
+        // wake up the collector once for every half ring, so events are handled in batches
+        if tail + 1 - ring.head.load(Ordering::SeqCst) == RING_CAPACITY / 2 {
+            self.wake_collector();
+        }
+    }
+
+    /// Gives up a ticket without sending anything.
+    pub fn cancel(&self, ticket: Ticket<T>) {
+        self.finish(ticket.ring);
+    }
+
+    fn finish(&self, ring: &Ring<T>) {
+        ring.inflight.store(IDLE, Ordering::SeqCst);
+        ring.lock.store(false, Ordering::SeqCst);
+    }
+
+    /// All events with a sequence number smaller than the watermark have been written into rings.
+    /// It must be read before draining the rings.
+    pub fn watermark(&self) -> u64 {
+        let mut watermark = self.sequence.load(Ordering::SeqCst);
+        for ring in self.iter() {
+            watermark = watermark.min(ring.inflight.load(Ordering::SeqCst));
+        }
+
+        watermark
+    }
+
+    /// Moves all written events out of the rings. It must only be called by the collector.
+    pub fn drain<F>(&self, mut f: F) -> usize
+    where
+        F: FnMut(u64, T),
+    {
+        let mut count = 0;
+        for ring in self.iter() {
+            let head = ring.head.load(Ordering::SeqCst);
+            let tail = ring.tail.load(Ordering::SeqCst);
+            for index in head..tail {
+                let (seq, item) =
+                    unsafe { (*ring.slots[index % RING_CAPACITY].get()).as_ptr().read() };
+                f(seq, item);
+            }
+            ring.head.store(tail, Ordering::SeqCst);
+            count += tail - head;
+        }
+
+        count
+    }
+
+    /// Number of events waiting in the rings.
+    pub fn len(&self) -> usize {
+        self.iter().map(|ring| ring.len()).sum()
+    }
+
+    /// Bytes of the event buffers of all rings.
+    pub fn buffer_bytes(&self) -> usize {
+        self.iter().count() * RING_CAPACITY * std::mem::size_of::<Slot<T>>()
+    }
+}
+
+struct Inner<T> {
+    queue: ArrayQueue<T>,
+    lock: Mutex<()>,
+    cond: Condvar,
+}
+
 pub struct Sender<T> {
-    queue: Arc<ArrayQueue<T>>
+    inner: Arc<Inner<T>>,
 }
 
 pub struct Receiver<T> {
-    queue: Arc<ArrayQueue<T>>
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> Inner<T> {
+    fn notify(&self) {
+        drop(self.lock.lock().unwrap());
+        self.cond.notify_all();
+    }
 }
 
 impl<T> Sender<T> {
+    /// Sends an item, blocking while the channel is full.
     pub fn send(&self, item: T) {
-        let backoff = Backoff::new();
-
         let mut item = item;
+        let mut guard = self.inner.lock.lock().unwrap();
         loop {
-            match self.queue.push(item) {
-                Ok(()) => {return}
+            match self.inner.queue.push(item) {
+                Ok(()) => {
+                    drop(guard);
+                    self.inner.cond.notify_all();
+                    return;
+                }
                 Err(PushError(left)) => {
-                    item = left
+                    item = left;
+                    guard = self.inner.cond.wait(guard).unwrap();
                 }
             }
-
-            backoff.snooze();
         }
     }
 }
 
 impl<T> Receiver<T> {
+    /// Receives an item, blocking while the channel is empty.
     pub fn recv(&self) -> T {
-        let backoff = Backoff::new();
-
+        let mut guard = self.inner.lock.lock().unwrap();
         loop {
-            match self.queue.pop() {
+            match self.inner.queue.pop() {
                 Ok(item) => {
+                    drop(guard);
+                    self.inner.cond.notify_all();
                     return item;
                 }
                 Err(_) => {
-                    backoff.snooze();
+                    guard = self.inner.cond.wait(guard).unwrap();
                 }
             }
         }
     }
+
+    pub fn try_recv(&self) -> Option<T> {
+        let item = self.inner.queue.pop().ok();
+        if item.is_some() {
+            self.inner.notify();
+        }
+
+        item
+    }
 }
 
+/// A blocking channel with a fixed capacity, which never allocates after being created.
 pub fn bounded<T>(size: usize) -> (Sender<T>, Receiver<T>) {
-    let queue = Arc::new(ArrayQueue::new(size));
+    let inner = Arc::new(Inner {
+        queue: ArrayQueue::new(size),
+        lock: Mutex::new(()),
+        cond: Condvar::new(),
+    });
+
+    (Sender { inner: inner.clone() }, Receiver { inner })
+}
 
-    return (Sender { queue: queue.clone() }, Receiver { queue: queue.clone()})
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send(registry: &Registry<u64>, ring: &'static Ring<u64>, item: u64) {
+        let ticket = registry.begin(ring);
+        registry.commit(ticket, item);
+    }
+
+    /// Drains until `count` events have been read, in the order they are read.
+    fn drain(registry: &Registry<u64>, count: usize) -> Vec<(u64, u64)> {
+        let mut events = Vec::new();
+        while events.len() < count {
+            registry.drain(|seq, item| events.push((seq, item)));
+            std::thread::yield_now();
+        }
+
+        events
+    }
+
+    /// Sends 1000 items numbered after `sender` into `ring`.
+    fn send_items(registry: &Registry<u64>, ring: &'static Ring<u64>, sender: u64) {
+        for index in 0..1000 {
+            send(registry, ring, sender * 1000 + index);
+        }
+    }
+
+    /// Checks the items of `sender` are read in the order they have been sent.
+    fn assert_sent_in_order(events: &[(u64, u64)], sender: u64) {
+        let sent: Vec<(u64, u64)> = events
+            .iter()
+            .copied()
+            .filter(|(_, item)| item / 1000 == sender)
+            .collect();
+        assert!(sent.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert!(sent.iter().map(|(_, item)| *item).eq(sender * 1000..sender * 1000 + 1000));
+    }
+
+    #[test]
+    fn drain_in_sequence_order() {
+        let registry = Registry::new();
+        let mut events = std::thread::scope(|scope| {
+            for thread in 0..4 {
+                let registry = &registry;
+                scope.spawn(move || {
+                    let ring = registry.acquire();
+                    send_items(registry, ring, thread);
+                    registry.release(ring);
+                });
+            }
+
+            drain(&registry, 4000)
+        });
+
+        for thread in 0..4 {
+            assert_sent_in_order(&events, thread);
+        }
+
+        // the sequence numbers merge the rings back into a single order
+        events.sort_unstable();
+        assert!(events.iter().map(|(seq, _)| *seq).eq(0..4000));
+    }
+
+    #[test]
+    fn watermark_waits_for_events_in_flight() {
+        let registry = Registry::new();
+        let first = registry.acquire();
+        let second = registry.acquire();
+        assert_eq!(registry.watermark(), 0);
+
+        let ticket = registry.begin(first);
+        send(&registry, second, 1);
+        assert_eq!(registry.sequence(), 2);
+        assert_eq!(registry.watermark(), 0);
+        assert_eq!(drain(&registry, 1), vec![(1, 1)]);
+
+        registry.commit(ticket, 0);
+        assert_eq!(registry.watermark(), 2);
+        assert_eq!(drain(&registry, 1), vec![(0, 0)]);
+
+        let ticket = registry.begin(second);
+        assert_eq!(registry.watermark(), 2);
+        registry.cancel(ticket);
+        assert_eq!(registry.watermark(), 3);
+        assert_eq!(registry.len(), 0);
+    }
+
+    #[test]
+    fn reuse_ring_of_exiting_thread() {
+        let registry = Registry::new();
+        let ring = registry.acquire();
+        registry.release(ring);
+
+        let reused = registry.acquire();
+        assert!(std::ptr::eq(ring, reused));
+        assert!(!std::ptr::eq(registry.acquire(), ring));
+
+        // the old owner still writes into the ring it has given back, next to the new owner
+        let events = std::thread::scope(|scope| {
+            for thread in 0..2 {
+                let registry = &registry;
+                scope.spawn(move || send_items(registry, ring, thread));
+            }
+
+            drain(&registry, 2000)
+        });
+
+        assert_eq!(events.len(), 2000);
+        assert!(events.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        for thread in 0..2 {
+            assert_sent_in_order(&events, thread);
+        }
+    }
+}
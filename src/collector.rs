@@ -1,171 +1,955 @@
-use crate::frame::{Frames, UnresolvedFrames};
-use crate::report::{Report, ReportReader};
-use std::collections::HashMap;
-use std::sync::{RwLock, Arc};
-use crate::channel::{bounded, Sender, Receiver};
+use crate::frame::{stack_hash, Backtrace, Frames, UnresolvedFrames};
+use crate::leaks::{LeakReport, LeakStats};
+use crate::lifetime::{self, Lifetimes};
+use crate::scope;
+use crate::sizes::SizeHistogram;
+use crate::maps::current_maps;
+use crate::raw::RawReport;
+use crate::stacks::StackTable;
+use crate::symbols::SymbolCache;
+use crate::stats::{Stats, COUNTERS};
+use crate::error::{Error, Result};
+use crate::report::{AllocStats, FreedStack, FreedStats, Report, ReportReader, Thread};
+use std::cell::Cell;
+use std::collections::{BinaryHeap, HashMap};
+use std::mem::size_of;
+use std::ops::AddAssign;
+use std::ptr::null;
+use std::sync::{RwLock, Mutex};
+use std::time::Duration;
+use crate::channel::{bounded, Receiver, Registry, Ring, Sender};
+use crate::profiler::{unrecorded, PROFILE};
 
-use crossbeam::queue::ArrayQueue;
 use crossbeam::sync::Parker;
-use std::sync::atomic::Ordering;
-use backtrace::Frame;
-use crate::MAX_DEPTH;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use crate::sampler::{self, Objects, RECORDED};
+
+/// A new peak snapshot is taken only when the live bytes exceed the last snapshot by this many
+/// bytes, or by 1/`PEAK_SNAPSHOT_RATIO` of the last snapshot if that is larger.
+const PEAK_SNAPSHOT_MIN_STEP: usize = 1 << 20;
+const PEAK_SNAPSHOT_RATIO: usize = 16;
 
 lazy_static::lazy_static! {
     pub(crate) static ref COLLECTOR: RwLock<Collector> = RwLock::new(Collector::default());
 }
 
+/// The thread which made an allocation, and the tag of its scope, 0 if there is none.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Origin {
+    thread: u32,
+    tag: u32,
+}
+
+/// A live allocation recorded in `ptr_map`: the id of its stack, its size in bytes, when it was
+/// made, from `lifetime::now`, and where it comes from.
+type Allocation = (u32, usize, u64, Origin);
+
+/// The live view, summed over all origins, by thread and by tag.
+type Live = HashMap<Frames, AllocStats>;
+
 #[derive(Default)]
 pub struct Collector {
-    backtrace_counter: HashMap<UnresolvedFrames, usize>,
-    ptr_map: HashMap<u64, (UnresolvedFrames, usize)>, // Map ptr to frames and original size
+    stacks: StackTable,
+    symbols: SymbolCache,
+
+    backtrace_counter: HashMap<(u32, Origin), AllocStats>, // Keyed by stack and origin
+    freed_counter: HashMap<(u32, u32), FreedStats>, // Keyed by alloc and free stack
+    lifetimes: HashMap<u32, Lifetimes>, // Only the freed allocations, the live are aged on report
+    sizes: HashMap<u32, SizeHistogram>,
+    ptr_map: HashMap<u64, Allocation>,
+    sample_interval: usize,
+    max_depth: usize,
+    trim_frames: bool,
+
+    live_bytes: usize,
+    peak_bytes: usize,
+    peak_snapshot: HashMap<(u32, Origin), AllocStats>,
+    peak_snapshot_bytes: usize,
+
+    threads: HashMap<u32, Thread>,
+
+    events: u64,
+    dropped_events: u64,
 }
 
 impl Collector {
-    pub fn init(&self) {
-        println!("MEM RECORD START")
+    pub fn new(sample_interval: usize, max_depth: usize, trim_frames: bool) -> Self {
+        Collector {
+            symbols: SymbolCache::new(trim_frames, max_depth),
+            sample_interval,
+            max_depth,
+            trim_frames,
+            ..Default::default()
+        }
     }
 
-    pub fn alloc(&mut self, addr: u64, size: usize, backtrace: UnresolvedFrames) {
-        match self.backtrace_counter.get_mut(&backtrace) {
-            Some(s) => {
-                *s += size;
-            }
-            None => {
-                self.backtrace_counter.insert(backtrace.clone(), size);
-            }
+    fn grow(&mut self, bytes: usize) {
+        self.live_bytes += bytes;
+        if self.live_bytes <= self.peak_bytes {
+            return;
         }
+        self.peak_bytes = self.live_bytes;
 
-        match self
-            .ptr_map
-            .insert(addr, (backtrace.clone(), size)) {
-            Some((frames, size)) => {
-                println!("WARN! DUPLICATE ALLOC: {} {}", Frames::from(frames.clone()), size);
-            }
-            None => {
+        let step = PEAK_SNAPSHOT_MIN_STEP.max(self.peak_snapshot_bytes / PEAK_SNAPSHOT_RATIO);
+        if self.peak_bytes >= self.peak_snapshot_bytes + step {
+            self.snapshot_peak();
+        }
+    }
+
+    fn shrink(&mut self, bytes: usize) {
+        self.live_bytes -= bytes;
+    }
+
+    fn snapshot_peak(&mut self) {
+        self.peak_snapshot = self.backtrace_counter.clone();
+        self.peak_snapshot_bytes = self.live_bytes;
+    }
 
+    /// Stacks are kept as unwound. The frames of the profiler are trimmed once they are resolved,
+    /// so raw reports are written without resolving any symbol.
+    pub fn stack(&mut self, hash: u64, frames: UnresolvedFrames) {
+        // on a collision of hashes, the events of the stack go to the one interned first
+        if self.stacks.intern(hash, frames).is_none() {
+            self.dropped_events += 1;
+        }
+    }
+
+    /// Translates the hash of a stack sent by a thread into its id. Threads send a stack before
+    /// its hash, so an unknown hash is counted as a dropped event.
+    fn stack_id(&mut self, hash: u64) -> Option<u32> {
+        let id = self.stacks.get(hash);
+        if id.is_none() {
+            self.dropped_events += 1;
+        }
+
+        id
+    }
+
+    fn frames(&mut self, stack: u32) -> Frames {
+        self.symbols.resolve(self.stacks.frames(stack))
+    }
+
+    /// Resolves the symbols of all stacks seen so far, so the next report is faster.
+    pub fn warm_symbols(&mut self) {
+        for frames in self.stacks.iter() {
+            self.symbols.warm(frames);
+        }
+    }
+
+    pub fn clear_symbols(&mut self) {
+        self.symbols.clear();
+    }
+
+    /// The bytes and number of allocations, in `OBJECT_UNIT`s, a recorded allocation of `size`
+    /// bytes stands for.
+    fn scale(&self, size: usize) -> (usize, usize) {
+        sampler::scale(size, self.sample_interval)
+    }
+
+    pub fn thread(&mut self, id: u32, name: Option<String>) {
+        self.threads.insert(id, Thread { id, name });
+    }
+
+    fn alloc(&mut self, addr: u64, size: usize, zeroed: bool, hash: u64, origin: Origin, time: u64) {
+        let stack = match self.stack_id(hash) {
+            Some(stack) => stack,
+            None => {
+                RECORDED.remove(addr);
+                return;
             }
+        };
+        let (bytes, objects) = self.scale(size);
+
+        let stats = self.backtrace_counter.entry((stack, origin)).or_default();
+        stats.live_bytes += bytes;
+        stats.live_objects += objects;
+        stats.total_bytes += bytes;
+        stats.total_objects += objects;
+        if zeroed {
+            stats.zeroed_bytes += bytes;
+            stats.zeroed_objects += objects;
+        }
+        self.grow(bytes);
+        self.sizes.entry(stack).or_default().add(size, objects);
+
+        if let Some((stack, size, _, _)) = self.ptr_map.insert(addr, (stack, size, time, origin)) {
+            RECORDED.remove(addr);
+            self.dropped_events += 1;
+            println!(
+                "WARN! DUPLICATE ALLOC: {} {}",
+                self.frames(stack),
+                self.scale(size).0
+            );
         }
     }
 
-    pub fn dealloc(&mut self, addr: u64, backtrace: UnresolvedFrames) {
-        match self.ptr_map.get(&addr) {
-            Some((bt, s)) => {
-                match self.backtrace_counter.get_mut(bt) {
-                    Some(size) => *size -= s,
-                    None => {
-                        println!("WARN UNRECORDED DEALLOC")
-                    }
+    /// `all_recorded` tells whether every allocation had been recorded when the memory was freed.
+    pub fn dealloc(&mut self, addr: u64, all_recorded: bool, hash: u64, time: u64) {
+        let (stack, size, allocated_at, origin) = match self.ptr_map.remove(&addr) {
+            Some(allocation) => allocation,
+            None => {
+                // the address filter could let through the deallocation of an address which has
+                // never been recorded, as it was not sampled or tracking was paused
+                if all_recorded {
+                    self.dropped_events += 1;
                 }
+                return;
+            }
+        };
+        RECORDED.remove(addr);
 
-                let complete_backtrace = UnresolvedFrames::new(
-                    &bt.frames
-                        .clone()
-                        .into_iter()
-                        .chain(backtrace.frames)
-                        .collect::<Vec<Frame>>(),
-                );
-
-                self.backtrace_counter.insert(complete_backtrace, *s);
+        let (bytes, objects) = self.scale(size);
+        match self.backtrace_counter.get_mut(&(stack, origin)) {
+            Some(stats) => {
+                stats.live_bytes -= bytes;
+                stats.live_objects -= objects;
             }
             None => {
+                self.dropped_events += 1;
                 println!("WARN UNRECORDED DEALLOC")
             }
+        }
+        self.shrink(bytes);
+
+        if let Some(free_stack) = self.stack_id(hash) {
+            let freed = self.freed_counter.entry((stack, free_stack)).or_default();
+            freed.bytes += bytes;
+            freed.objects += objects;
+        }
+
+        self.lifetimes
+            .entry(stack)
+            .or_default()
+            .add_freed(time.saturating_sub(allocated_at), objects);
+    }
+
+    /// The reallocated memory keeps the stack, the time and the origin of the original allocation.
+    fn realloc(
+        &mut self,
+        old_addr: u64,
+        all_recorded: bool,
+        new_addr: u64,
+        new_size: usize,
+        hash: u64,
+        origin: Origin,
+    ) {
+        let (stack, size, allocated_at, owner) = match self.ptr_map.remove(&old_addr) {
+            Some(allocation) => allocation,
+            None => {
+                // the old address has never been recorded, so is the new one
+                RECORDED.remove(new_addr);
+                if all_recorded {
+                    self.dropped_events += 1;
+                }
+                return;
+            }
         };
+        RECORDED.remove(old_addr);
 
-        match self.ptr_map.remove(&addr) {
-            Some(_) => {},
-            None => {println!("WARN UNRECORDED DEALLOC")}
+        // the weight of a sample is given by its current size
+        let (old_bytes, old_objects) = self.scale(size);
+        let (bytes, objects) = self.scale(new_size);
+
+        match self.backtrace_counter.get_mut(&(stack, owner)) {
+            Some(stats) => {
+                stats.live_bytes = stats.live_bytes - old_bytes + bytes;
+                stats.live_objects = stats.live_objects - old_objects + objects;
+                stats.total_bytes += bytes.saturating_sub(old_bytes);
+            }
+            None => self.dropped_events += 1,
+        }
+        // shrink first, so the stats have already been updated when a snapshot is taken
+        self.shrink(old_bytes);
+        self.grow(bytes);
+        if let Some(realloc_stack) = self.stack_id(hash) {
+            self.backtrace_counter
+                .entry((realloc_stack, origin))
+                .or_default()
+                .reallocs += old_objects;
+        }
+        self.sizes.entry(stack).or_default().add(new_size, objects);
+
+        if let Some((stack, size, _, _)) = self
+            .ptr_map
+            .insert(new_addr, (stack, new_size, allocated_at, owner))
+        {
+            RECORDED.remove(new_addr);
+            self.dropped_events += 1;
+            println!(
+                "WARN! DUPLICATE ALLOC: {} {}",
+                self.frames(stack),
+                self.scale(size).0
+            );
         }
     }
 
-    pub fn report(&self) -> Report {
+    fn resolve<V>(
+        stacks: &StackTable,
+        symbols: &mut SymbolCache,
+        counter: &HashMap<u32, V>,
+    ) -> HashMap<Frames, V>
+    where
+        V: AddAssign + Clone + Default + Objects,
+    {
+        let mut live: HashMap<Frames, V> = HashMap::new();
+        for (stack, stats) in counter.iter() {
+            // different addresses could be resolved into the same symbols
+            *live.entry(symbols.resolve(stacks.frames(*stack))).or_default() += stats.clone();
+        }
+
+        live.into_iter()
+            .map(|(frames, stats)| (frames, stats.round_objects()))
+            .collect()
+    }
+
+    /// The lifetimes of the freed allocations of every stack, with the live ones aged until now.
+    fn lifetimes(&self) -> HashMap<u32, Lifetimes> {
+        let now = lifetime::now();
+
+        let mut lifetimes = self.lifetimes.clone();
+        for (stack, size, allocated_at, _) in self.ptr_map.values() {
+            let (_, objects) = self.scale(*size);
+            lifetimes
+                .entry(*stack)
+                .or_default()
+                .add_live(now.saturating_sub(*allocated_at), objects);
+        }
+
+        lifetimes
+    }
+
+    /// Report of the heap at its high-water mark. The snapshot is taken lazily, so it could be
+    /// a little smaller than the real peak, but never by more than the snapshot step.
+    pub fn peak_report(&mut self) -> Report {
+        if self.live_bytes == self.peak_bytes && self.peak_bytes > self.peak_snapshot_bytes {
+            self.snapshot_peak();
+        }
+
+        let (live, threads, tags) = Self::live(
+            &self.stacks,
+            &mut self.symbols,
+            &self.threads,
+            &self.peak_snapshot,
+        );
+
         Report {
-            data: self
+            live,
+            threads,
+            tags,
+            freed: HashMap::new(),
+            lifetimes: HashMap::new(),
+            sizes: HashMap::new(),
+            stats: stats(),
+            maps: current_maps(),
+        }
+    }
+
+    /// Resolves the live view of `counter`, summed over all origins, by thread and by tag. Untagged
+    /// allocations are left out of the view by tag.
+    fn live(
+        stacks: &StackTable,
+        symbols: &mut SymbolCache,
+        threads: &HashMap<u32, Thread>,
+        counter: &HashMap<(u32, Origin), AllocStats>,
+    ) -> (Live, HashMap<Thread, Live>, HashMap<String, Live>) {
+        let mut by_stack: HashMap<u32, AllocStats> = HashMap::new();
+        let mut by_thread: HashMap<u32, HashMap<u32, AllocStats>> = HashMap::new();
+        let mut by_tag: HashMap<u32, HashMap<u32, AllocStats>> = HashMap::new();
+        for ((stack, origin), stats) in counter.iter() {
+            *by_stack.entry(*stack).or_default() += *stats;
+            *by_thread.entry(origin.thread).or_default().entry(*stack).or_default() += *stats;
+            if origin.tag != 0 {
+                *by_tag.entry(origin.tag).or_default().entry(*stack).or_default() += *stats;
+            }
+        }
+
+        let live = Self::resolve(stacks, symbols, &by_stack);
+        let by_thread = by_thread
+            .into_iter()
+            .map(|(id, counter)| {
+                let thread = threads.get(&id).cloned().unwrap_or(Thread { id, name: None });
+                (thread, Self::resolve(stacks, symbols, &counter))
+            })
+            .collect();
+
+        let tags = scope::names();
+        let by_tag = by_tag
+            .into_iter()
+            .map(|(id, counter)| {
+                let tag = tags.get(&id).cloned().unwrap_or_else(|| format!("tag {}", id));
+                (tag, Self::resolve(stacks, symbols, &counter))
+            })
+            .collect();
+
+        (live, by_thread, by_tag)
+    }
+
+    pub fn report(&mut self) -> Report {
+        let (live, threads, tags) = Self::live(
+            &self.stacks,
+            &mut self.symbols,
+            &self.threads,
+            &self.backtrace_counter,
+        );
+
+        let mut freed: HashMap<FreedStack, FreedStats> = HashMap::new();
+        for ((alloc, free), stats) in self.freed_counter.iter() {
+            let stack = FreedStack {
+                alloc: self.symbols.resolve(self.stacks.frames(*alloc)),
+                free: self.symbols.resolve(self.stacks.frames(*free)),
+            };
+            *freed.entry(stack).or_default() += *stats;
+        }
+        freed.values_mut().for_each(|stats| *stats = stats.round_objects());
+
+        let lifetimes = self.lifetimes();
+        let lifetimes = Self::resolve(&self.stacks, &mut self.symbols, &lifetimes);
+        let sizes = Self::resolve(&self.stacks, &mut self.symbols, &self.sizes);
+
+        Report {
+            live,
+            threads,
+            tags,
+            freed,
+            lifetimes,
+            sizes,
+            stats: stats(),
+            maps: current_maps(),
+        }
+    }
+
+    /// Report with stacks kept as instruction addresses, to be resolved away from the process.
+    pub fn raw_report(&self) -> RawReport {
+        RawReport {
+            maps: current_maps(),
+            stacks: self.stacks.iter().map(|frames| frames.frames.clone()).collect(),
+            trim_frames: self.trim_frames,
+            max_depth: self.max_depth,
+            threads: self.threads.values().cloned().collect(),
+            tags: scope::names().into_iter().collect(),
+            live: self
                 .backtrace_counter
                 .iter()
-                .map(|(frames, size)| (Frames::from(frames.clone()), *size))
+                .map(|((stack, origin), stats)| {
+                    ((*stack as usize, origin.thread, origin.tag), stats.round_objects())
+                })
+                .collect(),
+            freed: self
+                .freed_counter
+                .iter()
+                .map(|((alloc, free), stats)| {
+                    ((*alloc as usize, *free as usize), stats.round_objects())
+                })
                 .collect(),
+            lifetimes: self
+                .lifetimes()
+                .into_iter()
+                .map(|(stack, lifetimes)| (stack as usize, lifetimes.round_objects()))
+                .collect(),
+            sizes: self
+                .sizes
+                .iter()
+                .map(|(stack, sizes)| (*stack as usize, sizes.clone().round_objects()))
+                .collect(),
+            stats: stats(),
         }
     }
+
+    /// Report of every allocation which has not been freed, grouped by its stack.
+    pub fn leak_report(&mut self) -> LeakReport {
+        let mut stacks: HashMap<u32, LeakStats> = HashMap::new();
+        for (stack, size, _, _) in self.ptr_map.values() {
+            let (bytes, objects) = self.scale(*size);
+            *stacks.entry(*stack).or_default() += LeakStats { bytes, objects };
+        }
+
+        let mut leaks: HashMap<Frames, LeakStats> = HashMap::new();
+        for (stack, stats) in stacks {
+            *leaks.entry(self.frames(stack)).or_default() += stats;
+        }
+        leaks.values_mut().for_each(|stats| *stats = stats.round_objects());
+
+        LeakReport { leaks }
+    }
+
+    /// Estimated bytes held by the collector.
+    fn heap_bytes(&self) -> usize {
+        fn map_bytes<K, V>(map: &HashMap<K, V>) -> usize {
+            map.capacity() * (size_of::<(K, V)>() + 1)
+        }
+
+        map_bytes(&self.backtrace_counter)
+            + map_bytes(&self.freed_counter)
+            + map_bytes(&self.ptr_map)
+            + map_bytes(&self.lifetimes)
+            + map_bytes(&self.sizes)
+            + map_bytes(&self.threads)
+            + map_bytes(&self.peak_snapshot)
+            + self.stacks.heap_bytes()
+            + self.symbols.heap_bytes()
+    }
+
+    /// Publishes the stats of the collector, with `pending` events drained but not handled yet.
+    fn publish(&self, pending: &BinaryHeap<Pending>) {
+        let stats = Stats {
+            events: self.events,
+            dropped_events: self.dropped_events,
+            tracked_allocations: self.ptr_map.len(),
+            stacks: self.stacks.len(),
+            collector_bytes: self.heap_bytes()
+                + pending.capacity() * size_of::<Pending>()
+                + EVENTS.buffer_bytes(),
+            ..Default::default()
+        };
+        COUNTERS.publish(&stats, pending.len());
+    }
+}
+
+/// The stats published by the collector.
+pub fn stats() -> Stats {
+    COUNTERS.stats(EVENTS.len())
 }
 
+/// Operations refer to stacks by their hash and threads by their id. A thread sends its name with
+/// `Thread` and the frames of a stack with `Stack` before their first use, so the events of hot
+/// stacks are only a few words.
 enum Operation {
-    Alloc(u64, usize, ([Frame; MAX_DEPTH], usize)),
-    Dealloc(u64, ([Frame; MAX_DEPTH], usize)),
+    Thread(u32, Option<String>),
+    Stack(u64, UnresolvedFrames),
+    Alloc(u64, usize, bool, u64, Origin, u64),
+    Dealloc(u64, bool, u64, u64),
+    Realloc(u64, bool, u64, usize, u64, Origin),
+}
+
+impl Collector {
+    fn handle(&mut self, operation: Operation) {
+        self.events += 1;
+        match operation {
+            Operation::Thread(id, name) => self.thread(id, name),
+            Operation::Stack(stack, frames) => self.stack(stack, frames),
+            Operation::Alloc(ptr, size, zeroed, stack, origin, time) => {
+                self.alloc(ptr, size, zeroed, stack, origin, time)
+            }
+            Operation::Dealloc(ptr, all_recorded, stack, time) => {
+                self.dealloc(ptr, all_recorded, stack, time)
+            }
+            Operation::Realloc(old_ptr, all_recorded, new_ptr, new_size, stack, origin) => {
+                self.realloc(old_ptr, all_recorded, new_ptr, new_size, stack, origin)
+            }
+        }
+    }
+}
+
+/// Commands are sent by the threads asking for reports. The sequence number tells which events
+/// must be handled before the report is taken.
+enum Command {
     DropReport(Report),
-    Report,
+    Report(u64),
+    PeakReport(u64),
+    RawReport(u64),
+    DropRawReport(RawReport),
+    LeakReport(u64),
+    WarmSymbols,
+    ClearSymbols,
+}
+
+static EVENTS: Registry<Operation> = Registry::new();
+
+/// Set once the collector thread is started. `EVENTS` must have a single collector draining it.
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Number of stacks cached by every thread.
+const LOCAL_STACKS: usize = 128;
+
+thread_local! {
+    static RING: Cell<*const Ring<Operation>> = const { Cell::new(null()) };
+    static LOCAL: Local = Local::new();
+    static THREAD: Cell<u32> = const { Cell::new(0) };
+}
+
+/// A stack cached by a thread with the tag of its allocations.
+struct LocalStack {
+    hash: Cell<u64>,
+    tag: Cell<u32>,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_STACK: LocalStack = LocalStack {
+    hash: Cell::new(0),
+    tag: Cell::new(0),
+};
+
+/// The state of a thread, which owns its ring and a direct mapped cache of the stacks it has sent.
+/// It gives the ring back when the thread exits. The thread could still use the ring afterwards,
+/// which is safe as producers of a ring are serialized.
+struct Local {
+    ring: Cell<*const Ring<Operation>>,
+    stacks: [LocalStack; LOCAL_STACKS],
+}
+
+impl Local {
+    fn new() -> Self {
+        Local {
+            ring: Cell::new(null()),
+            stacks: [EMPTY_STACK; LOCAL_STACKS],
+        }
+    }
+
+    /// Slots are keyed by stack and tag, so the tasks interleaved on a thread do not evict the
+    /// stacks of each other.
+    fn slot(&self, stack: u64, tag: u32) -> &LocalStack {
+        &self.stacks[((stack >> 40) ^ tag as u64) as usize % LOCAL_STACKS]
+    }
+
+    /// Makes sure the collector knows the stack before its id is sent, and takes the slot of the
+    /// stack with `tag`.
+    fn register(&self, stack: u64, tag: u32, backtrace: &[u64]) {
+        let slot = self.slot(stack, tag);
+        if slot.hash.get() == stack && slot.tag.get() == tag {
+            return;
+        }
+
+        slot.hash.set(stack);
+        slot.tag.set(tag);
+        send(Operation::Stack(stack, unresolved(backtrace)));
+    }
+}
+
+impl Drop for Local {
+    fn drop(&mut self) {
+        let ring = self.ring.get();
+        if !ring.is_null() {
+            EVENTS.release(unsafe { &*ring });
+        }
+    }
+}
+
+fn unresolved(backtrace: &[u64]) -> UnresolvedFrames {
+    unrecorded(|| UnresolvedFrames::new(backtrace))
+}
+
+fn local_ring() -> &'static Ring<Operation> {
+    RING.with(|ring| {
+        if ring.get().is_null() {
+            // the ring and the thread local destructor may allocate, which must not be recorded
+            unrecorded(|| {
+                let acquired = EVENTS.acquire();
+                let _ = LOCAL.try_with(|local| local.ring.set(acquired));
+                ring.set(acquired);
+            });
+        }
+
+        unsafe { &*ring.get() }
+    })
+}
+
+/// Threads are numbered from 1 in the order they first send an event.
+static NEXT_THREAD: AtomicU32 = AtomicU32::new(1);
+
+/// Gives the id of the current thread, sending its name to the collector the first time.
+fn local_thread() -> u32 {
+    THREAD.with(|thread| {
+        if thread.get() == 0 {
+            let id = NEXT_THREAD.fetch_add(1, Ordering::SeqCst);
+            thread.set(id);
+
+            let name = unrecorded(|| std::thread::current().name().map(|name| name.to_owned()));
+            send(Operation::Thread(id, name));
+        }
+
+        thread.get()
+    })
+}
+
+fn local_origin() -> Origin {
+    Origin {
+        thread: local_thread(),
+        tag: scope::current(),
+    }
+}
+
+/// Gives the hash of a stack, sending its frames to the collector if the current thread has not
+/// cached it with `tag`.
+fn local_stack((frames, depth): &Backtrace, tag: u32) -> u64 {
+    let backtrace = &frames[0..*depth];
+    let stack = stack_hash(backtrace);
+
+    // the ring must be acquired first, as it initializes the thread local state
+    local_ring();
+    if LOCAL
+        .try_with(|local| local.register(stack, tag, backtrace))
+        .is_err()
+    {
+        // the thread is exiting, so the stack cannot be cached
+        send(Operation::Stack(stack, unresolved(backtrace)));
+    }
+
+    stack
+}
+
+fn send(operation: Operation) {
+    let ticket = EVENTS.begin(local_ring());
+    EVENTS.commit(ticket, operation);
+}
+
+/// An operation drained from the rings, ordered by its sequence number.
+struct Pending(u64, Operation);
+
+impl PartialEq for Pending {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl Eq for Pending {}
+
+impl PartialOrd for Pending {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Pending {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        // reversed, so the binary heap pops the smallest sequence number first
+        other.0.cmp(&self.0)
+    }
+}
+
+/// Drains all rings and handles the operations in the order in which they happened. Operations
+/// after the watermark stay pending, as an earlier one could still be in flight. Returns the number
+/// of drained operations and the watermark.
+fn collect(collector: &mut Collector, pending: &mut BinaryHeap<Pending>) -> (usize, u64) {
+    let watermark = EVENTS.watermark();
+    let drained = EVENTS.drain(|seq, operation| pending.push(Pending(seq, operation)));
+
+    while pending.peek().is_some_and(|operation| operation.0 < watermark) {
+        if let Some(Pending(_, operation)) = pending.pop() {
+            collector.handle(operation);
+        }
+    }
+
+    (drained, watermark)
+}
+
+/// Handles all operations happened before `seq`.
+fn catch_up(collector: &mut Collector, pending: &mut BinaryHeap<Pending>, seq: u64) {
+    while collect(collector, pending).1 < seq {
+        std::thread::yield_now();
+    }
 }
 
 pub struct CollectorClient {
-    operation_sender: Sender<Operation>,
+    command_sender: Sender<Command>,
     report_receiver: Receiver<Report>,
+    raw_receiver: Receiver<RawReport>,
+    leak_receiver: Receiver<LeakReport>,
+    report_lock: Mutex<()>,
 }
 
-impl Default for CollectorClient {
-    fn default() -> Self {
-        let mut collector = Collector::default();
-        let (operation_sender, operation_receiver) = bounded(1);
+impl CollectorClient {
+    /// Starts the collector thread. It fails with `Error::AlreadyInitialized` if it has already
+    /// been started.
+    pub fn new(sample_interval: usize, max_depth: usize, trim_frames: bool) -> Result<Self> {
+        if STARTED.swap(true, Ordering::SeqCst) {
+            return Err(Error::AlreadyInitialized);
+        }
+
+        let mut collector = Collector::new(sample_interval, max_depth, trim_frames);
+        let (command_sender, command_receiver) = bounded(16);
         let (report_sender, report_receiver) = bounded(1);
+        let (raw_sender, raw_receiver) = bounded(1);
+        let (leak_sender, leak_receiver) = bounded(1);
+
+        lifetime::start();
+
+        let parker = Parker::new();
+        EVENTS.set_collector(parker.unparker().clone());
 
-        let mut p = Parker::new();
+        let p = Parker::new();
         let u = p.unparker().clone();
 
-        std::thread::Builder::new()
+        let spawned = std::thread::Builder::new()
             .name("collector".to_owned())
             .spawn(move || {
-                use crate::profiler::PROFILE;
-
                 PROFILE.with(|profile| {
                     profile.store(false, Ordering::SeqCst);
                 });
 
                 u.unpark();
+
+                let mut pending = BinaryHeap::new();
                 loop {
-                    match operation_receiver.recv() {
-                        Operation::Alloc(ptr, size, (frames, depth)) => {
-                            collector.alloc(ptr, size, UnresolvedFrames::new(&frames[0..depth]))
+                    let (drained, _) = collect(&mut collector, &mut pending);
+                    collector.publish(&pending);
+
+                    match command_receiver.try_recv() {
+                        Some(Command::Report(seq)) => {
+                            catch_up(&mut collector, &mut pending, seq);
+                            collector.publish(&pending);
+                            report_sender.send(collector.report());
                         }
-                        Operation::Dealloc(ptr, (frames, depth)) => {
-                            collector.dealloc(ptr, UnresolvedFrames::new(&frames[0..depth]))
+                        Some(Command::PeakReport(seq)) => {
+                            catch_up(&mut collector, &mut pending, seq);
+                            collector.publish(&pending);
+                            report_sender.send(collector.peak_report());
                         }
-                        Operation::Report => {
-                            report_sender.send(collector.report());
+                        Some(Command::RawReport(seq)) => {
+                            catch_up(&mut collector, &mut pending, seq);
+                            collector.publish(&pending);
+                            raw_sender.send(collector.raw_report());
+                        }
+                        Some(Command::LeakReport(seq)) => {
+                            catch_up(&mut collector, &mut pending, seq);
+                            collector.publish(&pending);
+                            leak_sender.send(collector.leak_report());
+                        }
+                        Some(Command::DropReport(report)) => {
+                            drop(report)
                         }
-                        Operation::DropReport(report) => {
+                        Some(Command::DropRawReport(report)) => {
                             drop(report)
                         }
+                        Some(Command::WarmSymbols) => collector.warm_symbols(),
+                        Some(Command::ClearSymbols) => collector.clear_symbols(),
+                        None => {
+                            if drained > 0 {
+                                continue;
+                            }
+
+                            if pending.is_empty() {
+                                parker.park();
+                            } else {
+                                // waiting for an operation in flight
+                                parker.park_timeout(Duration::from_millis(1));
+                            }
+                        }
                     }
                 }
-        }).unwrap();
+        });
+        if let Err(err) = spawned {
+            // nothing drains the rings, so a collector could still be started later
+            STARTED.store(false, Ordering::SeqCst);
+            return Err(err.into());
+        }
 
         p.park();
 
-        CollectorClient {
-            operation_sender,
+        Ok(CollectorClient {
+            command_sender,
             report_receiver,
+            raw_receiver,
+            leak_receiver,
+            report_lock: Mutex::new(()),
+        })
+    }
+
+    fn command(&self, command: Command) {
+        self.command_sender.send(command);
+        EVENTS.wake_collector();
+    }
+
+    pub fn alloc(&self, addr: u64, size: usize, zeroed: bool, backtrace: Backtrace) {
+        let origin = local_origin();
+        let stack = local_stack(&backtrace, origin.tag);
+        send(Operation::Alloc(addr, size, zeroed, stack, origin, lifetime::now()));
+    }
+
+    /// `all_recorded` tells whether every allocation has been recorded, so an unknown `addr` is
+    /// counted as a dropped event.
+    pub fn dealloc(&self, addr: u64, all_recorded: bool, backtrace: Backtrace) {
+        let stack = local_stack(&backtrace, scope::current());
+        send(Operation::Dealloc(addr, all_recorded, stack, lifetime::now()));
+    }
+
+    /// Records a reallocation done by `realloc`. The operation is ordered before `realloc` is
+    /// called, as the old address could be reused by another thread as soon as it returns.
+    pub fn realloc<F>(
+        &self,
+        old_addr: u64,
+        all_recorded: bool,
+        new_size: usize,
+        backtrace: Backtrace,
+        realloc: F,
+    ) -> *mut u8
+    where
+        F: FnOnce() -> *mut u8,
+    {
+        let origin = local_origin();
+        let stack = local_stack(&backtrace, origin.tag);
+        let ticket = EVENTS.begin(local_ring());
+
+        let new_ptr = realloc();
+        if new_ptr.is_null() {
+            EVENTS.cancel(ticket);
+        } else {
+            let operation =
+                Operation::Realloc(old_addr, all_recorded, new_ptr as u64, new_size, stack, origin);
+            EVENTS.commit(ticket, operation);
         }
+
+        new_ptr
     }
-}
 
-impl CollectorClient {
-    pub fn alloc(&self, addr: u64, size: usize, backtrace: ([Frame; MAX_DEPTH], usize)) {
-        self.operation_sender.send(Operation::Alloc(addr, size, backtrace));
+    pub fn stats(&self) -> Stats {
+        stats()
     }
 
-    pub fn dealloc(&self, addr: u64, backtrace: ([Frame; MAX_DEPTH], usize)) {
-        self.operation_sender.send(Operation::Dealloc(addr, backtrace));
+    pub fn warm_symbols(&self) {
+        self.command(Command::WarmSymbols);
+    }
+
+    pub fn clear_symbols(&self) {
+        self.command(Command::ClearSymbols);
     }
 
     pub fn drop_report(&self, report: Report) {
-        self.operation_sender.send(Operation::DropReport(report));
+        self.command(Command::DropReport(report));
     }
 
-    pub fn report(&self) -> ReportReader {
-        self.operation_sender.send(Operation::Report);
+    pub fn report(&self) -> ReportReader<'_> {
+        let _guard = self.report_lock.lock().unwrap();
+        self.command(Command::Report(EVENTS.sequence()));
 
         let report = self.report_receiver.recv();
         let report_reader = ReportReader::new(report, self);
 
         report_reader
     }
-}
\ No newline at end of file
+
+    /// Hands the raw report to `f`. The report is given back to the collector afterwards, as it
+    /// has been allocated there.
+    pub fn raw_report<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&RawReport) -> R,
+    {
+        let report = {
+            let _guard = self.report_lock.lock().unwrap();
+            self.command(Command::RawReport(EVENTS.sequence()));
+
+            self.raw_receiver.recv()
+        };
+
+        let result = f(&report);
+        self.command(Command::DropRawReport(report));
+
+        result
+    }
+
+    /// The report is allocated by the collector, so it must be dropped while unrecorded.
+    pub fn leak_report(&self) -> LeakReport {
+        let _guard = self.report_lock.lock().unwrap();
+        self.command(Command::LeakReport(EVENTS.sequence()));
+
+        self.leak_receiver.recv()
+    }
+
+    pub fn peak_report(&self) -> ReportReader<'_> {
+        let _guard = self.report_lock.lock().unwrap();
+        self.command(Command::PeakReport(EVENTS.sequence()));
+
+        let report = self.report_receiver.recv();
+
+        ReportReader::new(report, self)
+    }
+}
@@ -0,0 +1,149 @@
+use crate::error::{Error, Result};
+use crate::frame::Frames;
+use crate::report::{fold, AllocStats, Metric, Report};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::io::Write;
+
+/// The statistics of one stack in two reports.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiffStats {
+    pub before: AllocStats,
+    pub after: AllocStats,
+}
+
+impl DiffStats {
+    /// The signed change of `metric` from the baseline.
+    pub fn delta(&self, metric: Metric) -> isize {
+        metric.value(&self.after) as isize - metric.value(&self.before) as isize
+    }
+
+    pub fn live_bytes(&self) -> isize {
+        self.delta(Metric::LiveBytes)
+    }
+
+    pub fn live_objects(&self) -> isize {
+        self.delta(Metric::LiveObjects)
+    }
+
+    pub fn total_bytes(&self) -> isize {
+        self.delta(Metric::TotalBytes)
+    }
+
+    pub fn total_objects(&self) -> isize {
+        self.delta(Metric::TotalObjects)
+    }
+}
+
+impl Display for DiffStats {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "live: {:+} bytes in {:+} objects, total: {:+} bytes in {:+} objects",
+            self.live_bytes(),
+            self.live_objects(),
+            self.total_bytes(),
+            self.total_objects()
+        )
+    }
+}
+
+/// DiffReport is the change of the live view between two reports, keyed by allocation stack.
+pub struct DiffReport {
+    pub data: HashMap<Frames, DiffStats>,
+}
+
+impl Report {
+    /// Compares the live view of this report with an earlier `baseline`.
+    pub fn diff(&self, baseline: &Report) -> DiffReport {
+        let mut data: HashMap<Frames, DiffStats> = HashMap::new();
+
+        for (frames, stats) in baseline.live.iter() {
+            data.entry(frames.clone()).or_default().before = *stats;
+        }
+        for (frames, stats) in self.live.iter() {
+            data.entry(frames.clone()).or_default().after = *stats;
+        }
+
+        DiffReport { data }
+    }
+}
+
+impl DiffReport {
+    /// Stacks sorted by the absolute change of `metric`, the largest first. Unchanged stacks are
+    /// skipped.
+    pub fn sorted(&self, metric: Metric) -> Vec<(&Frames, &DiffStats)> {
+        let mut stacks: Vec<(&Frames, &DiffStats)> = self
+            .data
+            .iter()
+            .filter(|(_, stats)| stats.delta(metric) != 0)
+            .collect();
+        stacks.sort_by_key(|(_, stats)| -stats.delta(metric).abs());
+
+        stacks
+    }
+
+    fn lines(&self, metric: Metric) -> Vec<String> {
+        self.data
+            .iter()
+            .map(|(frames, stats)| (frames, metric.value(&stats.before), metric.value(&stats.after)))
+            .filter(|(_, before, after)| *before > 0 || *after > 0)
+            .map(|(frames, before, after)| format!("{} {} {}", fold(&[frames]), before, after))
+            .collect()
+    }
+
+    /// Writes the stacks in the differential collapsed format, one `root;..;leaf before after`
+    /// line for every stack, as produced by `inferno-diff-folded`.
+    pub fn write_folded<W>(&self, metric: Metric, mut writer: W) -> std::io::Result<()>
+    where
+        W: Write,
+    {
+        for line in self.lines(metric) {
+            writeln!(writer, "{}", line)?;
+        }
+
+        Ok(())
+    }
+
+    /// Renders a differential flamegraph. Frames are sized by the new report, and colored red
+    /// where `metric` grows and blue where it shrinks. Fails with `Error::NoStacks` if no stack
+    /// has a value in either report.
+    pub fn flamegraph<W>(&self, metric: Metric, writer: W) -> Result<()>
+    where
+        W: Write,
+    {
+        use inferno::flamegraph;
+
+        let lines = self.lines(metric);
+        if lines.is_empty() {
+            return Err(Error::NoStacks);
+        }
+
+        let mut options = flamegraph::Options {
+            hash: true,
+            count_name: metric.unit().to_owned(),
+            ..Default::default()
+        };
+        flamegraph::from_lines(&mut options, lines.iter().map(|s| &**s), writer)?;
+
+        Ok(())
+    }
+}
+
+impl Display for DiffReport {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        let mut stacks: Vec<(&Frames, &DiffStats)> = self
+            .data
+            .iter()
+            .filter(|(_, stats)| stats.before != stats.after)
+            .collect();
+        stacks.sort_by_key(|(_, stats)| -stats.live_bytes().abs());
+
+        for (key, val) in stacks {
+            write!(f, "{} {}", key, val)?;
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
+}
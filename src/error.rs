@@ -0,0 +1,59 @@
+use std::fmt::{Display, Formatter};
+
+#[derive(Debug)]
+pub enum Error {
+    /// The collector has not been started by `AllocRecorder::init_collector`.
+    NotInitialized,
+
+    /// The collector has already been started. There is one per process, as all recorders share
+    /// the event buffers.
+    AlreadyInitialized,
+
+    /// Failed to spawn the collector thread, or to write a report.
+    Io(std::io::Error),
+
+    /// Failed to render a flamegraph.
+    Flamegraph(quick_xml::Error),
+
+    /// No stack has a value of the metric, so there is nothing to render.
+    NoStacks,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            Error::NotInitialized => write!(f, "collector is not initialized"),
+            Error::AlreadyInitialized => write!(f, "collector is already initialized"),
+            Error::Io(err) => write!(f, "io error: {}", err),
+            Error::Flamegraph(err) => write!(f, "failed to render flamegraph: {}", err),
+            Error::NoStacks => write!(f, "no stack to render"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::NotInitialized | Error::AlreadyInitialized | Error::NoStacks => None,
+            Error::Io(err) => Some(err),
+            Error::Flamegraph(err) => Some(err),
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<quick_xml::Error> for Error {
+    fn from(err: quick_xml::Error) -> Self {
+        match err {
+            quick_xml::Error::Io(err) => Error::Io(err),
+            err => Error::Flamegraph(err),
+        }
+    }
+}
@@ -1,4 +1,3 @@
-use backtrace::Frame;
 use rustc_demangle::demangle;
 use std::fmt::{Display, Formatter};
 use std::hash::{Hash, Hasher};
@@ -7,40 +6,61 @@ use std::path::PathBuf;
 
 use crate::MAX_DEPTH;
 
-#[derive(Clone)]
+/// Frames unwound beyond the maximum depth when the frames of the profiler are trimmed, as they do
+/// not count toward it. Stacks are cut to the maximum depth once they are resolved and trimmed.
+pub(crate) const PROFILER_DEPTH: usize = 32;
+
+/// A stack captured on the allocating thread: the instruction address of every frame, with the
+/// innermost first and the unwinding depth.
+pub(crate) type Backtrace = ([u64; MAX_DEPTH + PROFILER_DEPTH], usize);
+
+/// The address standing for the frames cut by the maximum depth.
+pub(crate) const TRUNCATED: u64 = u64::MAX;
+
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
 pub struct UnresolvedFrames {
-    pub frames: Vec<Frame>,
+    pub frames: Vec<u64>,
 }
 
 impl UnresolvedFrames {
-    pub fn new(bt: &[Frame]) -> Self {
+    pub fn new(bt: &[u64]) -> Self {
         Self { frames: bt.to_vec() }
     }
 }
 
-impl PartialEq for UnresolvedFrames {
-    fn eq(&self, other: &Self) -> bool {
-        if self.frames.len() == other.frames.len() {
-            let iter = self.frames.iter().zip(other.frames.iter());
+/// Prefixes of the functions run between the allocation and the global allocator.
+const PROFILER_FRAMES: [&str; 9] = [
+    "backtrace::",
+    "cogito::",
+    "<cogito::",
+    "std::thread::local::",
+    "<std::thread::local::",
+    "__rust_",
+    "__rustc",
+    "alloc::alloc::",
+    "<alloc::alloc::Global as core::alloc::",
+];
 
-            iter.map(|(self_frame, other_frame)| {
-                self_frame.symbol_address() == other_frame.symbol_address()
-            })
-            .all(|result| result)
-        } else {
-            false
-        }
-    }
-}
+fn is_profiler_frame(symbol: &Symbol) -> bool {
+    let name = match symbol.name.as_ref().map(|name| std::str::from_utf8(name)) {
+        Some(Ok(name)) => name,
+        _ => return false,
+    };
 
-impl Eq for UnresolvedFrames {}
+    // the alternate form leaves out the hashes and the crate disambiguators of both manglings
+    let name = format!("{:#}", demangle(name));
+    PROFILER_FRAMES.iter().any(|prefix| name.starts_with(prefix))
+}
 
-impl Hash for UnresolvedFrames {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.frames
-            .iter()
-            .for_each(|frame| frame.symbol_address().hash(state));
+/// Identifies a stack by hashing the instruction addresses of its frames, so it can be computed on
+/// the allocating thread without resolving anything. It is never 0.
+pub(crate) fn stack_hash(frames: &[u64]) -> u64 {
+    let mut hash: u64 = 0;
+    for addr in frames {
+        hash = (hash.rotate_left(5) ^ addr).wrapping_mul(0x517c_c1b7_2722_0a95);
     }
+
+    (hash ^ (hash >> 32)).max(1)
 }
 
 /// Symbol is a representation of a function symbol. It contains name and addr of it. If built with
@@ -61,9 +81,19 @@ pub struct Symbol {
 }
 
 impl Symbol {
+    /// The frame standing for the frames cut by the maximum depth.
+    fn truncated() -> Self {
+        Symbol {
+            name: Some(b"[truncated]".to_vec()),
+            addr: None,
+            lineno: None,
+            filename: None,
+        }
+    }
+
     pub fn name(&self) -> String {
         match &self.name {
-            Some(name) => match std::str::from_utf8(&name) {
+            Some(name) => match std::str::from_utf8(name) {
                 Ok(name) => format!("{}", demangle(name)),
                 Err(_) => "NonUtf8Name".to_owned(),
             },
@@ -73,7 +103,7 @@ impl Symbol {
 
     pub fn sys_name(&self) -> &str {
         match &self.name {
-            Some(name) => match std::str::from_utf8(&name) {
+            Some(name) => match std::str::from_utf8(name) {
                 Ok(name) => name,
                 Err(_) => "NonUtf8Name",
             },
@@ -97,18 +127,19 @@ impl Symbol {
 }
 
 unsafe impl Send for Symbol {}
+unsafe impl Sync for Symbol {}
 
 impl From<&backtrace::Symbol> for Symbol {
     fn from(symbol: &backtrace::Symbol) -> Self {
         Symbol {
             name: symbol
                 .name()
-                .and_then(|name| Some(name.as_bytes().to_vec())),
+                .map(|name| name.as_bytes().to_vec()),
             addr: symbol.addr(),
             lineno: symbol.lineno(),
             filename: symbol
                 .filename()
-                .and_then(|filename| Some(filename.to_owned())),
+                .map(|filename| filename.to_owned()),
         }
     }
 }
@@ -116,7 +147,7 @@ impl From<&backtrace::Symbol> for Symbol {
 impl Display for Symbol {
     fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
         match &self.name {
-            Some(name) => match std::str::from_utf8(&name) {
+            Some(name) => match std::str::from_utf8(name) {
                 Ok(name) => write!(f, "{}", demangle(name))?,
                 Err(_) => write!(f, "NonUtf8Name")?,
             },
@@ -124,13 +155,11 @@ impl Display for Symbol {
                 write!(f, "Unknown")?;
             }
         }
-        match &self.filename {
-            Some(filename) => write!(f, ":{:?}", filename)?,
-            None => {}
+        if let Some(filename) = &self.filename {
+            write!(f, ":{:?}", filename)?;
         }
-        match &self.lineno {
-            Some(lineno) => write!(f,":{}", lineno)?,
-            None => {}
+        if let Some(lineno) = &self.lineno {
+            write!(f, ":{}", lineno)?;
         }
         Ok(())
     }
@@ -151,38 +180,80 @@ impl PartialEq for Symbol {
 #[derive(Debug, Clone)]
 pub struct Frames {
     pub frames: Vec<Vec<Symbol>>,
+
+    /// Instruction address of every frame. It is not taken into account when comparing frames, so
+    /// stacks resolved into the same symbols are equal.
+    pub addresses: Vec<u64>,
 }
 
-impl From<UnresolvedFrames> for Frames {
-    fn from(frames: UnresolvedFrames) -> Self {
-        let mut fs = Vec::new();
-        frames.frames.iter().for_each(|frame| {
-            let mut symbols = Vec::new();
-            backtrace::resolve_frame(frame, |symbol| {
-                symbols.push(Symbol::from(symbol));
-            });
-            fs.push(symbols);
+/// Resolves the symbols of an instruction address. Inlined functions come first, and the last
+/// one is their caller.
+pub(crate) fn resolve_addr(addr: u64) -> Vec<Symbol> {
+    let mut symbols = Vec::new();
+    if addr == TRUNCATED {
+        symbols.push(Symbol::truncated());
+    } else {
+        backtrace::resolve(addr as *mut c_void, |symbol| {
+            symbols.push(Symbol::from(symbol));
         });
+    }
+
+    symbols
+}
+
+impl Frames {
+    /// Removes the innermost frames which belong to the profiler or to the allocator shims, so
+    /// stacks start from the code which allocated, then keeps at most `max_depth` frames. If any
+    /// frame is cut, the outermost one kept is replaced with a truncation marker.
+    pub(crate) fn trim_profiler_frames(&mut self, max_depth: usize) {
+        let profiler_frames = self
+            .frames
+            .iter()
+            // a frame belongs to its last symbol, as the ones before have been inlined into it
+            .take_while(|symbols| symbols.last().is_some_and(is_profiler_frame))
+            .count();
 
-        Self { frames: fs }
+        // keep the stack if nothing but the profiler is found, as the symbols may be missing
+        if profiler_frames < self.frames.len() {
+            self.frames.drain(..profiler_frames);
+            self.addresses.drain(..profiler_frames);
+        }
+
+        if self.frames.len() > max_depth {
+            self.frames.truncate(max_depth);
+            self.addresses.truncate(max_depth);
+            if let Some(last) = self.frames.last_mut() {
+                *last = vec![Symbol::truncated()];
+            }
+            if let Some(last) = self.addresses.last_mut() {
+                *last = TRUNCATED;
+            }
+        }
+    }
+}
+
+impl From<UnresolvedFrames> for Frames {
+    fn from(frames: UnresolvedFrames) -> Self {
+        Self {
+            frames: frames.frames.iter().map(|addr| resolve_addr(*addr)).collect(),
+            addresses: frames.frames,
+        }
     }
 }
 
 impl PartialEq for Frames {
     fn eq(&self, other: &Self) -> bool {
         if self.frames.len() == other.frames.len() {
-            let iter = self.frames.iter().zip(other.frames.iter());
+            let mut iter = self.frames.iter().zip(other.frames.iter());
 
-            iter.map(|(self_frame, other_frame)| {
+            iter.all(|(self_frame, other_frame)| {
                 if self_frame.len() == other_frame.len() {
-                    let iter = self_frame.iter().zip(other_frame.iter());
-                    iter.map(|(self_symbol, other_symbol)| self_symbol == other_symbol)
-                        .all(|result| result)
+                    let mut iter = self_frame.iter().zip(other_frame.iter());
+                    iter.all(|(self_symbol, other_symbol)| self_symbol == other_symbol)
                 } else {
                     false
                 }
             })
-            .all(|result| result)
         } else {
             false
         }
@@ -214,3 +285,62 @@ impl Display for Frames {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbol(name: &str) -> Vec<Symbol> {
+        vec![Symbol {
+            name: Some(name.as_bytes().to_vec()),
+            addr: None,
+            lineno: None,
+            filename: None,
+        }]
+    }
+
+    fn frames(names: &[&str]) -> Frames {
+        Frames {
+            frames: names.iter().map(|name| symbol(name)).collect(),
+            addresses: (1..=names.len() as u64).collect(),
+        }
+    }
+
+    const STACK: [&str; 6] = [
+        "_ZN6cogito8profiler13get_backtrace17h0123456789abcdefE",
+        "__rust_alloc",
+        "_ZN5alloc5alloc5alloc17h0123456789abcdefE",
+        "_ZN3app6caller17h0123456789abcdefE",
+        "_ZN3app4main17h0123456789abcdefE",
+        "main",
+    ];
+
+    #[test]
+    fn trim_profiler_frames() {
+        let mut stack = frames(&STACK);
+        stack.trim_profiler_frames(8);
+
+        assert_eq!(stack, frames(&STACK[3..]));
+        assert_eq!(stack.addresses, vec![4, 5, 6]);
+    }
+
+    #[test]
+    fn truncate_after_trimming() {
+        let mut stack = frames(&STACK);
+        stack.trim_profiler_frames(3);
+        assert_eq!(stack.addresses, vec![4, 5, 6]);
+
+        stack.trim_profiler_frames(2);
+        assert_eq!(stack.frames[0], symbol(STACK[3]));
+        assert_eq!(stack.frames[1][0].name(), "[truncated]");
+        assert_eq!(stack.addresses, vec![4, TRUNCATED]);
+    }
+
+    #[test]
+    fn keep_profiler_only_stacks() {
+        let mut stack = frames(&STACK[..3]);
+        stack.trim_profiler_frames(8);
+
+        assert_eq!(stack, frames(&STACK[..3]));
+    }
+}
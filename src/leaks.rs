@@ -0,0 +1,132 @@
+use crate::collector::CollectorClient;
+use crate::frame::Frames;
+use crate::profiler::unrecorded;
+use crate::sampler::{self, Objects};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::Write;
+use std::ops::AddAssign;
+use std::os::raw::c_int;
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+/// Where the leak report is written at exit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LeakOutput {
+    Stderr,
+    Path(&'static str),
+}
+
+/// The allocations from one stack which have never been freed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LeakStats {
+    pub bytes: usize,
+    pub objects: usize,
+}
+
+impl AddAssign for LeakStats {
+    fn add_assign(&mut self, other: LeakStats) {
+        self.bytes += other.bytes;
+        self.objects += other.objects;
+    }
+}
+
+impl Objects for LeakStats {
+    fn round_objects(self) -> Self {
+        LeakStats {
+            objects: sampler::objects(self.objects),
+            ..self
+        }
+    }
+}
+
+impl Display for LeakStats {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "leaked: {} bytes in {} objects", self.bytes, self.objects)
+    }
+}
+
+/// LeakReport lists every allocation still tracked by the collector, grouped by allocation stack.
+pub struct LeakReport {
+    pub leaks: HashMap<Frames, LeakStats>,
+}
+
+impl LeakReport {
+    pub fn is_empty(&self) -> bool {
+        self.leaks.is_empty()
+    }
+
+    /// The sum over all stacks.
+    pub fn total(&self) -> LeakStats {
+        let mut total = LeakStats::default();
+        for stats in self.leaks.values() {
+            total += *stats;
+        }
+
+        total
+    }
+}
+
+impl Display for LeakReport {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        let total = self.total();
+        writeln!(
+            f,
+            "LEAKS: {} bytes in {} objects from {} stacks",
+            total.bytes,
+            total.objects,
+            self.leaks.len()
+        )?;
+
+        // the largest leaks first
+        let mut leaks: Vec<(&Frames, &LeakStats)> = self.leaks.iter().collect();
+        leaks.sort_by_key(|(_, stats)| std::cmp::Reverse(stats.bytes));
+        for (key, val) in leaks {
+            write!(f, "{} {}", key, val)?;
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+struct ExitHook {
+    client: &'static CollectorClient,
+    output: LeakOutput,
+}
+
+static EXIT_HOOK: AtomicPtr<ExitHook> = AtomicPtr::new(null_mut());
+
+extern "C" {
+    fn atexit(callback: extern "C" fn()) -> c_int;
+}
+
+/// Writes the leaks recorded by `client` to `output` when the process exits. Only the last
+/// registered client is reported.
+pub(crate) fn report_at_exit(client: &'static CollectorClient, output: LeakOutput) {
+    let hook = unrecorded(|| Box::leak(Box::new(ExitHook { client, output })));
+    if EXIT_HOOK.swap(hook, Ordering::SeqCst).is_null() {
+        unsafe {
+            atexit(report_leaks);
+        }
+    }
+}
+
+extern "C" fn report_leaks() {
+    let hook = unsafe { &*EXIT_HOOK.load(Ordering::SeqCst) };
+
+    // the report is allocated by the collector, so it must be dropped unrecorded
+    unrecorded(|| {
+        let report = hook.client.leak_report();
+        let result = match hook.output {
+            LeakOutput::Stderr => write!(std::io::stderr(), "{}", report),
+            LeakOutput::Path(path) => {
+                File::create(path).and_then(|mut file| write!(file, "{}", report))
+            }
+        };
+        if let Err(err) = result {
+            eprintln!("failed to write leak report: {}", err);
+        }
+    });
+}
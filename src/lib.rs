@@ -1,11 +1,37 @@
-#![feature(const_fn)]
-
 mod collector;
 mod frame;
 mod report;
 mod profiler;
 mod channel;
+mod sampler;
+mod stacks;
+mod stats;
+mod symbols;
+mod unwind;
+mod maps;
+mod pprof;
+mod raw;
+mod resolve;
+mod diff;
+mod leaks;
+mod lifetime;
+mod sizes;
+mod scope;
+mod error;
 
-pub const MAX_DEPTH: usize = 128;
+/// The upper limit of `AllocRecorder::max_depth`.
+pub const MAX_DEPTH: usize = 256;
 
-pub use profiler::*;
\ No newline at end of file
+pub use profiler::*;
+pub use report::{AllocStats, FreedStack, FreedStats, Metric, Report, Thread};
+pub use diff::{DiffReport, DiffStats};
+pub use error::{Error, Result};
+pub use leaks::{LeakOutput, LeakReport, LeakStats};
+pub use lifetime::{Lifetimes, LIFETIME_BUCKETS};
+pub use maps::MemoryMap;
+pub use sizes::{SizeClasses, SizeHistogram};
+pub use raw::RawReport;
+pub use resolve::Resolver;
+pub use scope::{scope, Instrumented, ScopeGuard, Tag};
+pub use stats::Stats;
+pub use unwind::Unwinder;
\ No newline at end of file
@@ -0,0 +1,116 @@
+use crate::sampler::{self, Objects};
+use std::fmt::{Display, Formatter};
+use std::ops::AddAssign;
+use std::time::{Duration, Instant};
+
+lazy_static::lazy_static! {
+    static ref EPOCH: Instant = Instant::now();
+}
+
+/// Starts the clock of the allocation timestamps.
+pub(crate) fn start() {
+    lazy_static::initialize(&EPOCH);
+}
+
+/// Nanoseconds since the collector started, which timestamp the allocation events.
+pub(crate) fn now() -> u64 {
+    EPOCH.elapsed().as_nanos() as u64
+}
+
+/// Number of buckets of `Lifetimes`.
+pub const LIFETIME_BUCKETS: usize = 9;
+
+/// Upper bounds of the buckets in nanoseconds, from 1µs to 10s. The last bucket holds longer
+/// lifetimes.
+const BOUNDS: [u64; LIFETIME_BUCKETS - 1] = [
+    1_000,
+    10_000,
+    100_000,
+    1_000_000,
+    10_000_000,
+    100_000_000,
+    1_000_000_000,
+    10_000_000_000,
+];
+
+const LABELS: [&str; LIFETIME_BUCKETS] = [
+    "<1µs", "<10µs", "<100µs", "<1ms", "<10ms", "<100ms", "<1s", "<10s", ">=10s",
+];
+
+/// Lifetimes are histograms of how long the allocations from one stack live, in decimal buckets
+/// from `<1µs` to `>=10s`. Short lived allocations are candidates for pooling or the stack, and
+/// old live ones could be leaks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Lifetimes {
+    /// Number of freed allocations, by how long they lived.
+    pub freed: [usize; LIFETIME_BUCKETS],
+
+    /// Number of live allocations, by how long ago they were made.
+    pub live: [usize; LIFETIME_BUCKETS],
+}
+
+/// Gives the bucket of a lifetime of `nanos` nanoseconds.
+fn bucket(nanos: u64) -> usize {
+    BOUNDS.iter().take_while(|bound| nanos >= **bound).count()
+}
+
+impl Lifetimes {
+    /// Gives the range of lifetimes counted by the bucket `index`.
+    pub fn bucket_range(index: usize) -> (Duration, Option<Duration>) {
+        let lower = if index == 0 { 0 } else { BOUNDS[index - 1] };
+        let upper = BOUNDS.get(index).copied();
+        (Duration::from_nanos(lower), upper.map(Duration::from_nanos))
+    }
+
+    pub(crate) fn add_freed(&mut self, nanos: u64, objects: usize) {
+        self.freed[bucket(nanos)] += objects;
+    }
+
+    pub(crate) fn add_live(&mut self, nanos: u64, objects: usize) {
+        self.live[bucket(nanos)] += objects;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.freed.iter().chain(self.live.iter()).all(|count| *count == 0)
+    }
+}
+
+impl AddAssign for Lifetimes {
+    fn add_assign(&mut self, other: Lifetimes) {
+        let buckets = self.freed.iter_mut().chain(self.live.iter_mut());
+        for (count, other) in buckets.zip(other.freed.iter().chain(other.live.iter())) {
+            *count += *other;
+        }
+    }
+}
+
+impl Objects for Lifetimes {
+    fn round_objects(self) -> Self {
+        Lifetimes {
+            freed: self.freed.map(sampler::objects),
+            live: self.live.map(sampler::objects),
+        }
+    }
+}
+
+fn write_buckets(f: &mut Formatter, buckets: &[usize; LIFETIME_BUCKETS]) -> std::fmt::Result {
+    let mut first = true;
+    for (label, count) in LABELS.iter().zip(buckets.iter()) {
+        if *count > 0 {
+            write!(f, "{}{}: {}", if first { "" } else { ", " }, label, count)?;
+            first = false;
+        }
+    }
+
+    Ok(())
+}
+
+impl Display for Lifetimes {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "freed after: [")?;
+        write_buckets(f, &self.freed)?;
+        write!(f, "], live for: [")?;
+        write_buckets(f, &self.live)?;
+        write!(f, "]")
+    }
+}
@@ -0,0 +1,102 @@
+use std::path::PathBuf;
+
+/// MemoryMap is an executable region of the process, as listed in `/proc/self/maps`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryMap {
+    pub start: u64,
+    pub end: u64,
+
+    /// Offset of the region in the mapped file.
+    pub offset: u64,
+
+    /// Path of the mapped file. It is empty for anonymous regions.
+    pub path: PathBuf,
+}
+
+impl MemoryMap {
+    pub fn contains(&self, addr: u64) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    /// Parses one line of `/proc/<pid>/maps`. Only executable regions are returned.
+    pub fn parse(line: &str) -> Option<MemoryMap> {
+        let mut fields = line.split_whitespace();
+
+        let mut range = fields.next()?.split('-');
+        let start = u64::from_str_radix(range.next()?, 16).ok()?;
+        let end = u64::from_str_radix(range.next()?, 16).ok()?;
+
+        let perms = fields.next()?;
+        if !perms.contains('x') {
+            return None;
+        }
+
+        let offset = u64::from_str_radix(fields.next()?, 16).ok()?;
+        let _dev = fields.next()?;
+        let _inode = fields.next()?;
+        let path = fields.collect::<Vec<&str>>().join(" ");
+
+        Some(MemoryMap {
+            start,
+            end,
+            offset,
+            path: PathBuf::from(path),
+        })
+    }
+}
+
+/// Reads the executable regions of the current process. It returns an empty list on platforms
+/// without `/proc`.
+pub fn current_maps() -> Vec<MemoryMap> {
+    match std::fs::read_to_string("/proc/self/maps") {
+        Ok(content) => content.lines().filter_map(MemoryMap::parse).collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_file() {
+        let line = "7f2c8a000000-7f2c8a1c5000 r-xp 00028000 fd:01 1311042    /usr/lib/x86_64-linux-gnu/libc.so.6";
+
+        assert_eq!(
+            MemoryMap::parse(line),
+            Some(MemoryMap {
+                start: 0x7f2c_8a00_0000,
+                end: 0x7f2c_8a1c_5000,
+                offset: 0x28000,
+                path: PathBuf::from("/usr/lib/x86_64-linux-gnu/libc.so.6"),
+            })
+        );
+    }
+
+    #[test]
+    fn parse_path_with_spaces() {
+        let line = "55d0c0a00000-55d0c0b00000 r-xp 00001000 08:02 42    /opt/my app/bin/server";
+
+        let map = MemoryMap::parse(line).unwrap();
+        assert_eq!(map.path, PathBuf::from("/opt/my app/bin/server"));
+    }
+
+    #[test]
+    fn parse_anonymous() {
+        let line = "7ffd5a1f2000-7ffd5a1f4000 r-xp 00000000 00:00 0";
+
+        let map = MemoryMap::parse(line).unwrap();
+        assert_eq!(map.start, 0x7ffd_5a1f_2000);
+        assert_eq!(map.end, 0x7ffd_5a1f_4000);
+        assert_eq!(map.path, PathBuf::new());
+        assert!(map.contains(0x7ffd_5a1f_2000));
+        assert!(!map.contains(0x7ffd_5a1f_4000));
+    }
+
+    #[test]
+    fn parse_not_executable() {
+        let line = "55d0c0c00000-55d0c0d00000 rw-p 00000000 00:00 0    [heap]";
+
+        assert_eq!(MemoryMap::parse(line), None);
+    }
+}
@@ -0,0 +1,271 @@
+use crate::frame::Symbol;
+use crate::maps::MemoryMap;
+use crate::report::{AllocStats, Report};
+use std::collections::HashMap;
+use std::io::Write;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+/// Sample types of the generated profile, in the order of the values of every sample. They follow
+/// the heap profiles of Go, so `go tool pprof` picks the right defaults.
+const SAMPLE_TYPES: [(&str, &str); 4] = [
+    ("alloc_objects", "count"),
+    ("alloc_space", "bytes"),
+    ("inuse_objects", "count"),
+    ("inuse_space", "bytes"),
+];
+
+fn sample_values(stats: &AllocStats) -> [i64; 4] {
+    [
+        stats.total_objects as i64,
+        stats.total_bytes as i64,
+        stats.live_objects as i64,
+        stats.live_bytes as i64,
+    ]
+}
+
+/// A minimal protobuf writer, which covers the wire types used by `profile.proto`.
+#[derive(Default)]
+struct Message {
+    buf: Vec<u8>,
+}
+
+impl Message {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.buf.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        self.buf.push(value as u8);
+    }
+
+    fn key(&mut self, field: u32, wire_type: u8) {
+        self.varint(((field as u64) << 3) | wire_type as u64);
+    }
+
+    fn uint64(&mut self, field: u32, value: u64) {
+        if value != 0 {
+            self.key(field, 0);
+            self.varint(value);
+        }
+    }
+
+    fn int64(&mut self, field: u32, value: i64) {
+        self.uint64(field, value as u64);
+    }
+
+    fn bytes(&mut self, field: u32, value: &[u8]) {
+        self.key(field, 2);
+        self.varint(value.len() as u64);
+        self.buf.extend_from_slice(value);
+    }
+
+    fn message(&mut self, field: u32, message: Message) {
+        self.bytes(field, &message.buf);
+    }
+
+    fn packed(&mut self, field: u32, values: impl Iterator<Item = u64>) {
+        let mut packed = Message::default();
+        values.for_each(|value| packed.varint(value));
+        if !packed.buf.is_empty() {
+            self.message(field, packed);
+        }
+    }
+}
+
+#[derive(Default)]
+struct StringTable {
+    strings: Vec<String>,
+    index: HashMap<String, i64>,
+}
+
+impl StringTable {
+    fn new() -> Self {
+        let mut table = StringTable::default();
+        // the first string must be empty
+        table.get("");
+        table
+    }
+
+    fn get(&mut self, s: &str) -> i64 {
+        if let Some(id) = self.index.get(s) {
+            return *id;
+        }
+
+        let id = self.strings.len() as i64;
+        self.strings.push(s.to_owned());
+        self.index.insert(s.to_owned(), id);
+        id
+    }
+}
+
+/// A location is identified by its address and the (name, filename, line) of its symbols.
+type LocationKey = (u64, Vec<(String, String, u32)>);
+
+/// Builds the tables of `profile.proto`. Ids of mappings, locations and functions start from 1, as
+/// 0 is reserved.
+struct Builder<'a> {
+    profile: Message,
+    strings: StringTable,
+    maps: &'a [MemoryMap],
+    used_maps: HashMap<usize, u64>,
+    locations: HashMap<LocationKey, u64>,
+    functions: HashMap<(String, String), u64>,
+}
+
+impl<'a> Builder<'a> {
+    fn new(maps: &'a [MemoryMap]) -> Self {
+        Builder {
+            profile: Message::default(),
+            strings: StringTable::new(),
+            maps,
+            used_maps: HashMap::new(),
+            locations: HashMap::new(),
+            functions: HashMap::new(),
+        }
+    }
+
+    fn value_type(&mut self, field: u32, ty: &str, unit: &str) {
+        let mut value_type = Message::default();
+        value_type.int64(1, self.strings.get(ty));
+        value_type.int64(2, self.strings.get(unit));
+        self.profile.message(field, value_type);
+    }
+
+    fn mapping(&mut self, addr: u64) -> u64 {
+        let index = match self.maps.iter().position(|map| map.contains(addr)) {
+            Some(index) => index,
+            None => return 0,
+        };
+        if let Some(id) = self.used_maps.get(&index) {
+            return *id;
+        }
+
+        let id = self.used_maps.len() as u64 + 1;
+        let map = self.maps[index].clone();
+        let mut mapping = Message::default();
+        mapping.uint64(1, id);
+        mapping.uint64(2, map.start);
+        mapping.uint64(3, map.end);
+        mapping.uint64(4, map.offset);
+        mapping.int64(5, self.strings.get(&map.path.to_string_lossy()));
+        mapping.uint64(7, 1); // has_functions
+        mapping.uint64(8, 1); // has_filenames
+        mapping.uint64(9, 1); // has_line_numbers
+        mapping.uint64(10, 1); // has_inline_frames
+        self.profile.message(3, mapping);
+
+        self.used_maps.insert(index, id);
+        id
+    }
+
+    fn function(&mut self, symbol: &Symbol) -> u64 {
+        let key = (symbol.name(), symbol.filename().to_owned());
+        if let Some(id) = self.functions.get(&key) {
+            return *id;
+        }
+
+        let id = self.functions.len() as u64 + 1;
+        let mut function = Message::default();
+        function.uint64(1, id);
+        function.int64(2, self.strings.get(&key.0));
+        function.int64(3, self.strings.get(symbol.sys_name()));
+        function.int64(4, self.strings.get(&key.1));
+        self.profile.message(5, function);
+
+        self.functions.insert(key, id);
+        id
+    }
+
+    fn location(&mut self, addr: u64, frame: &[Symbol]) -> u64 {
+        let key: LocationKey = (
+            addr,
+            frame
+                .iter()
+                .map(|symbol| (symbol.name(), symbol.filename().to_owned(), symbol.lineno()))
+                .collect(),
+        );
+        if let Some(id) = self.locations.get(&key) {
+            return *id;
+        }
+
+        let id = self.locations.len() as u64 + 1;
+        let mut location = Message::default();
+        location.uint64(1, id);
+        location.uint64(2, self.mapping(addr));
+        location.uint64(3, addr);
+        // inlined functions come first, and the last one is their caller
+        for symbol in frame.iter() {
+            let mut line = Message::default();
+            line.uint64(1, self.function(symbol));
+            line.int64(2, symbol.lineno() as i64);
+            location.message(4, line);
+        }
+        self.profile.message(4, location);
+
+        self.locations.insert(key, id);
+        id
+    }
+
+    fn sample(&mut self, locations: &[u64], values: &[i64]) {
+        let mut sample = Message::default();
+        sample.packed(1, locations.iter().copied());
+        sample.packed(2, values.iter().map(|value| *value as u64));
+        self.profile.message(2, sample);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let time_nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|duration| duration.as_nanos() as i64)
+            .unwrap_or(0);
+        self.profile.int64(9, time_nanos);
+
+        let default_sample_type = self.strings.get("inuse_space");
+        self.profile.int64(14, default_sample_type);
+
+        for s in self.strings.strings.iter() {
+            self.profile.bytes(6, s.as_bytes());
+        }
+
+        self.profile.buf
+    }
+}
+
+impl Report {
+    /// Writes the live view in the gzip compressed `profile.proto` format of pprof, with the
+    /// sample types `alloc_objects`, `alloc_space`, `inuse_objects` and `inuse_space`. Mappings
+    /// are taken from the regions recorded in the report, so they match its addresses.
+    pub fn pprof<W>(&self, writer: W) -> std::io::Result<()>
+    where
+        W: Write,
+    {
+        let mut builder = Builder::new(&self.maps);
+        for (ty, unit) in SAMPLE_TYPES.iter() {
+            builder.value_type(1, ty, unit);
+        }
+
+        for (frames, stats) in self.live.iter() {
+            // stacks which only reallocate have no value
+            let values = sample_values(stats);
+            if values == [0; 4] {
+                continue;
+            }
+
+            let locations: Vec<u64> = frames
+                .frames
+                .iter()
+                .zip(frames.addresses.iter())
+                .map(|(frame, addr)| builder.location(*addr, frame))
+                .collect();
+            builder.sample(&locations, &values);
+        }
+
+        let mut encoder = GzEncoder::new(writer, Compression::default());
+        encoder.write_all(&builder.finish())?;
+        encoder.finish()?;
+
+        Ok(())
+    }
+}
@@ -1,47 +1,109 @@
 use std::alloc::{GlobalAlloc, Layout};
 
-use crate::frame::UnresolvedFrames;
-use crate::report::{Report, ReportReader};
-use crate::collector::{Collector, CollectorClient};
+use crate::frame::{Backtrace, PROFILER_DEPTH, TRUNCATED};
+use crate::report::ReportReader;
+use crate::collector::CollectorClient;
+use crate::sampler::{self, RECORDED};
+use crate::error::{Error, Result};
+use crate::leaks::{self, LeakOutput};
+use crate::stats::{Stats, COUNTERS};
+use crate::unwind::{self, Unwinder};
 
+use std::cell::Cell;
+use std::marker::PhantomData;
 use std::ptr::null_mut;
 use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
-use std::sync::RwLock;
+use std::io::Write;
+use std::time::Instant;
 
 use crate::MAX_DEPTH;
-use backtrace::Frame;
 
 thread_local! {
-    pub static PROFILE: AtomicBool = AtomicBool::new(true);
+    pub(crate) static PROFILE: AtomicBool = const { AtomicBool::new(true) };
+    static TRACKED: Cell<bool> = const { Cell::new(true) };
 }
 
-fn get_backtrace() -> ([Frame; MAX_DEPTH], usize) {
-    let mut skip = 0;
+/// The default of `AllocRecorder::max_depth`.
+const DEFAULT_MAX_DEPTH: usize = 128;
 
-    let mut bt: [Frame; MAX_DEPTH] = unsafe { std::mem::MaybeUninit::uninit().assume_init() };
-    let mut index = 0;
+/// Unwinds at most `max_depth` frames. If the stack is deeper, its outermost frame is replaced
+/// with a truncation marker.
+fn get_backtrace(max_depth: usize, unwinder: Unwinder) -> Backtrace {
+    let start = Instant::now();
 
-    backtrace::trace(|frame| {
-        if skip > 0 {
-            skip -= 1;
-            true
-        } else {
-            if index < MAX_DEPTH {
-                bt[index] = frame.clone();
-                index += 1;
-                true
-            } else {
-                false
-            }
+    let mut bt = [0; MAX_DEPTH + PROFILER_DEPTH];
+    let (depth, truncated) = unwind::trace(unwinder, &mut bt[..max_depth]);
+    if truncated && depth > 0 {
+        bt[depth - 1] = TRUNCATED;
+    }
+    COUNTERS.add_unwind_time(start.elapsed());
+
+    (bt, depth)
+}
+
+/// Runs `f` without recording its allocations.
+pub(crate) fn unrecorded<F: FnOnce() -> R, R>(f: F) -> R {
+    PROFILE.with(|profile| {
+        let enabled = profile.swap(false, Ordering::SeqCst);
+        let result = f();
+        profile.store(enabled, Ordering::SeqCst);
+
+        result
+    })
+}
+
+/// UntrackedGuard stops recording the allocations of the current thread until it is dropped. The
+/// memory allocated meanwhile never shows up in reports, and the frees and reallocations of memory
+/// which has been recorded before are still recorded.
+#[must_use = "tracking resumes when the guard is dropped"]
+pub struct UntrackedGuard {
+    previous: bool,
+
+    // the flag belongs to the current thread
+    _marker: PhantomData<*const ()>,
+}
+
+impl UntrackedGuard {
+    pub fn new() -> Self {
+        let previous = TRACKED.with(|tracked| tracked.replace(false));
+
+        UntrackedGuard {
+            previous,
+            _marker: PhantomData,
         }
-    });
+    }
+}
+
+impl Default for UntrackedGuard {
+    fn default() -> Self {
+        UntrackedGuard::new()
+    }
+}
 
-    (bt, index)
+impl Drop for UntrackedGuard {
+    fn drop(&mut self) {
+        let _ = TRACKED.try_with(|tracked| tracked.set(self.previous));
+    }
+}
+
+/// Runs `f` without recording the allocations it makes on the current thread.
+pub fn untracked<F: FnOnce() -> R, R>(f: F) -> R {
+    let _guard = UntrackedGuard::new();
+    f()
 }
 
 pub struct AllocRecorder<T: GlobalAlloc> {
     pub inner: T,
     pub collector: AtomicPtr<CollectorClient>,
+    sample_interval: usize,
+    max_depth: usize,
+    trim_frames: bool,
+    unwinder: Unwinder,
+    leak_output: Option<LeakOutput>,
+    paused: AtomicBool,
+
+    /// Set once an allocation is not recorded as tracking is off.
+    skipped: AtomicBool,
 }
 
 impl<T: GlobalAlloc> AllocRecorder<T> {
@@ -49,22 +111,166 @@ impl<T: GlobalAlloc> AllocRecorder<T> {
         AllocRecorder {
             inner,
             collector: AtomicPtr::new(null_mut()),
+            sample_interval: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
+            trim_frames: true,
+            unwinder: Unwinder::Backtrace,
+            leak_output: None,
+            paused: AtomicBool::new(false),
+            skipped: AtomicBool::new(false),
+        }
+    }
+
+    /// Record only a sample of allocations, on average one every `bytes` allocated bytes. Sizes in
+    /// the report are scaled back to estimate all allocations. `0` (the default) records every
+    /// allocation.
+    pub const fn sample_interval(mut self, bytes: usize) -> AllocRecorder<T> {
+        self.sample_interval = bytes;
+        self
+    }
+
+    /// Record at most `depth` frames of every stack, up to `MAX_DEPTH`. Deeper stacks end with a
+    /// `[truncated]` frame. The frames removed by `trim_frames` are not counted. The default is 128.
+    pub const fn max_depth(mut self, depth: usize) -> AllocRecorder<T> {
+        self.max_depth = if depth > MAX_DEPTH { MAX_DEPTH } else { depth };
+        self
+    }
+
+    /// Remove the frames of the profiler and of the allocator shims, e.g. `__rust_alloc`, from the
+    /// top of every stack when it is resolved. It is enabled by default.
+    pub const fn trim_frames(mut self, trim: bool) -> AllocRecorder<T> {
+        self.trim_frames = trim;
+        self
+    }
+
+    /// Select how stacks are captured. The default is `Unwinder::Backtrace`.
+    pub const fn unwinder(mut self, unwinder: Unwinder) -> AllocRecorder<T> {
+        self.unwinder = unwinder;
+        self
+    }
+
+    /// Report every allocation which is still live when the process exits, grouped by allocation
+    /// stack, like LeakSanitizer does. Allocations freed by thread local or static destructors
+    /// running after the report could show up as leaks.
+    pub const fn report_leaks(mut self, output: LeakOutput) -> AllocRecorder<T> {
+        self.leak_output = Some(output);
+        self
+    }
+
+    fn backtrace(&self) -> Backtrace {
+        // the stack is cut to `max_depth` once it is resolved and the profiler frames are trimmed
+        let headroom = if self.trim_frames { PROFILER_DEPTH } else { 0 };
+        get_backtrace(self.max_depth + headroom, self.unwinder)
+    }
+
+    /// Stop recording new allocations on all threads, e.g. around a phase which is not of
+    /// interest. The frees and reallocations of memory which has been recorded are still recorded,
+    /// so the live view stays right.
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    fn tracking(&self) -> bool {
+        !self.is_paused() && TRACKED.try_with(|tracked| tracked.get()).unwrap_or(true)
+    }
+
+    /// Whether every allocation has been recorded so far, so the free of an address the collector
+    /// does not know means an event has been lost.
+    fn all_recorded(&self) -> bool {
+        self.sample_interval == 0 && !self.skipped.load(Ordering::SeqCst)
+    }
+
+    fn sampled_alloc(&self, addr: u64, size: usize) -> bool {
+        if !self.tracking() {
+            if !self.skipped.load(Ordering::SeqCst) {
+                self.skipped.store(true, Ordering::SeqCst);
+            }
+            return false;
+        }
+
+        if self.sample_interval == 0 || sampler::sample(size, self.sample_interval) {
+            RECORDED.insert(addr);
+            true
+        } else {
+            false
         }
     }
 
-    pub fn init_collector(&self) {
-        let collector = Box::new(CollectorClient::default());
+    fn sampled_dealloc(&self, addr: u64) -> bool {
+        RECORDED.contains(addr)
+    }
+
+    /// Starts the collector. It can be started once per process, and later calls fail with
+    /// `Error::AlreadyInitialized`.
+    pub fn init_collector(&self) -> Result<()> {
+        let collector = Box::new(CollectorClient::new(
+            self.sample_interval,
+            self.max_depth,
+            self.trim_frames,
+        )?);
+        let collector: &'static CollectorClient = Box::leak(collector);
 
-        self.collector.store(Box::leak(collector), Ordering::SeqCst);
+        self.collector.store(collector as *const _ as *mut _, Ordering::SeqCst);
+        if let Some(output) = self.leak_output {
+            leaks::report_at_exit(collector, output);
+        }
+
+        Ok(())
+    }
+
+    fn client(&self) -> Result<&CollectorClient> {
+        let collector = self.collector.load(Ordering::SeqCst);
+        if collector.is_null() {
+            Err(Error::NotInitialized)
+        } else {
+            Ok(unsafe { &*collector })
+        }
     }
 
-    pub fn report(&self) -> ReportReader {
-        let report = unsafe {
-            (*self.collector.load(Ordering::SeqCst))
-                .report()
-        };
+    pub fn report(&self) -> Result<ReportReader<'_>> {
+        Ok(self.client()?.report())
+    }
 
-        report
+    /// Returns the heap at the moment the live bytes reached their maximum.
+    pub fn peak_report(&self) -> Result<ReportReader<'_>> {
+        Ok(self.client()?.peak_report())
+    }
+
+    /// Writes the current report without resolving any symbol, so it can be resolved later with
+    /// `Resolver`, e.g. on another machine which has the debug info. The frames of the profiler are
+    /// left for the resolver to trim. See `RawReport::write` for the format.
+    pub fn write_raw_report<W>(&self, writer: W) -> Result<()>
+    where
+        W: Write,
+    {
+        self.client()?.raw_report(|report| report.write(writer))?;
+        Ok(())
+    }
+
+    /// Resolves the symbols of all stacks recorded so far in the background, so the next report
+    /// does not have to. Resolved symbols are kept until `clear_symbols` is called.
+    pub fn warm_symbols(&self) -> Result<()> {
+        self.client()?.warm_symbols();
+        Ok(())
+    }
+
+    /// Drops the resolved symbols, e.g. after a library has been unloaded.
+    pub fn clear_symbols(&self) -> Result<()> {
+        self.client()?.clear_symbols();
+        Ok(())
+    }
+
+    /// Returns the overhead of the profiler, as last published by the collector.
+    pub fn stats(&self) -> Result<Stats> {
+        Ok(self.client()?.stats())
     }
 }
 
@@ -72,13 +278,30 @@ unsafe impl<T: GlobalAlloc> GlobalAlloc for AllocRecorder<T> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let ptr = self.inner.alloc(layout);
 
-        let addr = std::mem::transmute(ptr);
+        let addr = ptr as u64;
         PROFILE.with(move |profile| {
             if profile.load(Ordering::SeqCst) {
                 let collector = self.collector.load(Ordering::SeqCst);
-                if !collector.is_null() {
+                if !collector.is_null() && self.sampled_alloc(addr, layout.size()) {
                     let collector = &*collector;
-                    collector.alloc(addr , layout.size(), get_backtrace());
+                    collector.alloc(addr, layout.size(), false, self.backtrace());
+                }
+            }
+        });
+
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc_zeroed(layout);
+
+        let addr = ptr as u64;
+        PROFILE.with(move |profile| {
+            if profile.load(Ordering::SeqCst) {
+                let collector = self.collector.load(Ordering::SeqCst);
+                if !collector.is_null() && self.sampled_alloc(addr, layout.size()) {
+                    let collector = &*collector;
+                    collector.alloc(addr, layout.size(), true, self.backtrace());
                 }
             }
         });
@@ -87,17 +310,55 @@ unsafe impl<T: GlobalAlloc> GlobalAlloc for AllocRecorder<T> {
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let addr = std::mem::transmute(ptr);
+        let addr = ptr as u64;
         PROFILE.with(move |profile| {
             if profile.load(Ordering::SeqCst) {
                 let collector = self.collector.load(Ordering::SeqCst);
-                if !collector.is_null() {
+                if !collector.is_null() && self.sampled_dealloc(addr) {
                     let collector = &*collector;
-                    collector.dealloc(addr , get_backtrace());
+                    collector.dealloc(addr, self.all_recorded(), self.backtrace());
                 }
             }
         });
 
         self.inner.dealloc(ptr, layout);
     }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let old_addr = ptr as u64;
+        let tracked = PROFILE.with(|profile| profile.load(Ordering::SeqCst))
+            && !self.collector.load(Ordering::SeqCst).is_null()
+            && self.sampled_dealloc(old_addr);
+        if tracked {
+            let collector = &*self.collector.load(Ordering::SeqCst);
+            let recorded = self.all_recorded();
+            return collector.realloc(old_addr, recorded, new_size, self.backtrace(), || {
+                let new_ptr = self.inner.realloc(ptr, layout, new_size);
+                if !new_ptr.is_null() {
+                    RECORDED.insert(new_ptr as u64);
+                }
+
+                new_ptr
+            });
+        }
+
+        let new_ptr = self.inner.realloc(ptr, layout, new_size);
+        if new_ptr.is_null() {
+            return new_ptr;
+        }
+
+        // the old address has not been recorded, so record the new one as an allocation
+        let new_addr = new_ptr as u64;
+        PROFILE.with(move |profile| {
+            if profile.load(Ordering::SeqCst) {
+                let collector = self.collector.load(Ordering::SeqCst);
+                if !collector.is_null() && self.sampled_alloc(new_addr, new_size) {
+                    let collector = &*collector;
+                    collector.alloc(new_addr, new_size, false, self.backtrace());
+                }
+            }
+        });
+
+        new_ptr
+    }
 }
@@ -0,0 +1,405 @@
+use crate::lifetime::Lifetimes;
+use crate::maps::MemoryMap;
+use crate::sizes::{SizeClasses, SizeHistogram};
+use crate::report::{AllocStats, FreedStats, Thread};
+use crate::stats::Stats;
+use std::io::{BufRead, Error, ErrorKind, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+
+const HEADER: &str = "cogito raw 1";
+
+/// RawReport is a report which has not been symbolized. Stacks are kept as instruction addresses
+/// together with the executable regions of the process, so they can be resolved later and on
+/// another machine by `Resolver`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RawReport {
+    pub maps: Vec<MemoryMap>,
+
+    /// Instruction addresses of every stack, the innermost first. Stacks are referred to by
+    /// their index.
+    pub stacks: Vec<Vec<u64>>,
+
+    /// Whether the frames of the profiler are still to be trimmed from the resolved stacks, which
+    /// are then cut to `max_depth` frames.
+    pub trim_frames: bool,
+    pub max_depth: usize,
+
+    pub threads: Vec<Thread>,
+
+    /// The names of the scope tags, keyed by their id.
+    pub tags: Vec<(u32, String)>,
+
+    /// Keyed by the index of the stack, the id of the allocating thread and the id of the tag, 0
+    /// for untagged allocations.
+    pub live: Vec<((usize, u32, u32), AllocStats)>,
+
+    /// Keyed by the index of the allocation and the free stack.
+    pub freed: Vec<((usize, usize), FreedStats)>,
+
+    pub lifetimes: Vec<(usize, Lifetimes)>,
+
+    pub sizes: Vec<(usize, SizeHistogram)>,
+
+    pub stats: Stats,
+}
+
+fn invalid(line: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("invalid line: {}", line))
+}
+
+fn parse<T, F>(field: Option<&str>, line: &str, parse: F) -> std::io::Result<T>
+where
+    F: FnOnce(&str) -> Option<T>,
+{
+    field.and_then(parse).ok_or_else(|| invalid(line))
+}
+
+fn hex(field: &str) -> Option<u64> {
+    u64::from_str_radix(field, 16).ok()
+}
+
+fn dec(field: &str) -> Option<usize> {
+    field.parse().ok()
+}
+
+impl RawReport {
+    /// Writes the report in a line based text format. Addresses are written in hex.
+    ///
+    /// ```text
+    /// cogito raw 1
+    /// stats <events> <dropped> <queued> <allocations> <stacks> <collector bytes> <unwind ns>
+    /// map <start> <end> <offset> <path>
+    /// stack <index> <address>...
+    /// trim <max depth>
+    /// thread <id> [<name>]
+    /// tag <id> <name>
+    /// live <stack> <thread> <tag> <live bytes> <live objects> <total bytes> <total objects> <reallocs> <zeroed bytes> <zeroed objects>
+    /// freed <alloc stack> <free stack> <bytes> <objects>
+    /// lifetime <stack> <freed objects of every bucket>... <live objects of every bucket>...
+    /// size <stack> <size class>:<objects>...
+    /// ```
+    pub fn write<W>(&self, mut writer: W) -> std::io::Result<()>
+    where
+        W: Write,
+    {
+        writeln!(writer, "{}", HEADER)?;
+
+        let stats = &self.stats;
+        writeln!(
+            writer,
+            "stats {} {} {} {} {} {} {}",
+            stats.events,
+            stats.dropped_events,
+            stats.queue_depth,
+            stats.tracked_allocations,
+            stats.stacks,
+            stats.collector_bytes,
+            stats.unwind_time.as_nanos()
+        )?;
+
+        for map in self.maps.iter() {
+            writeln!(
+                writer,
+                "map {:x} {:x} {:x} {}",
+                map.start,
+                map.end,
+                map.offset,
+                map.path.display()
+            )?;
+        }
+
+        for (index, stack) in self.stacks.iter().enumerate() {
+            write!(writer, "stack {}", index)?;
+            for addr in stack.iter() {
+                write!(writer, " {:x}", addr)?;
+            }
+            writeln!(writer)?;
+        }
+
+        if self.trim_frames {
+            writeln!(writer, "trim {}", self.max_depth)?;
+        }
+
+        for thread in self.threads.iter() {
+            match &thread.name {
+                Some(name) => writeln!(writer, "thread {} {}", thread.id, name)?,
+                None => writeln!(writer, "thread {}", thread.id)?,
+            }
+        }
+
+        for (id, name) in self.tags.iter() {
+            writeln!(writer, "tag {} {}", id, name)?;
+        }
+
+        for ((stack, thread, tag), stats) in self.live.iter() {
+            writeln!(
+                writer,
+                "live {} {} {} {} {} {} {} {} {} {}",
+                stack,
+                thread,
+                tag,
+                stats.live_bytes,
+                stats.live_objects,
+                stats.total_bytes,
+                stats.total_objects,
+                stats.reallocs,
+                stats.zeroed_bytes,
+                stats.zeroed_objects
+            )?;
+        }
+
+        for ((alloc, free), stats) in self.freed.iter() {
+            writeln!(writer, "freed {} {} {} {}", alloc, free, stats.bytes, stats.objects)?;
+        }
+
+        for (stack, lifetimes) in self.lifetimes.iter() {
+            write!(writer, "lifetime {}", stack)?;
+            for count in lifetimes.freed.iter().chain(lifetimes.live.iter()) {
+                write!(writer, " {}", count)?;
+            }
+            writeln!(writer)?;
+        }
+
+        for (stack, sizes) in self.sizes.iter() {
+            write!(writer, "size {}", stack)?;
+            for (class, objects) in sizes.buckets(SizeClasses::Jemalloc) {
+                write!(writer, " {}:{}", class, objects)?;
+            }
+            writeln!(writer)?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads a report written by `write`.
+    pub fn read<R>(reader: R) -> std::io::Result<RawReport>
+    where
+        R: BufRead,
+    {
+        let mut lines = reader.lines();
+        match lines.next() {
+            Some(Ok(line)) if line == HEADER => {}
+            Some(Err(err)) => return Err(err),
+            _ => return Err(Error::new(ErrorKind::InvalidData, "not a raw cogito report")),
+        }
+
+        let mut report = RawReport::default();
+        for line in lines {
+            let line = line?;
+            let mut fields = line.split(' ');
+            match fields.next() {
+                Some("stats") => {
+                    let mut next = || parse(fields.next(), &line, dec);
+                    report.stats = Stats {
+                        events: next()? as u64,
+                        dropped_events: next()? as u64,
+                        queue_depth: next()?,
+                        tracked_allocations: next()?,
+                        stacks: next()?,
+                        collector_bytes: next()?,
+                        unwind_time: Duration::from_nanos(next()? as u64),
+                    };
+                }
+                Some("map") => {
+                    let start = parse(fields.next(), &line, hex)?;
+                    let end = parse(fields.next(), &line, hex)?;
+                    let offset = parse(fields.next(), &line, hex)?;
+                    // the path is the rest of the line, which could contain spaces
+                    let path = PathBuf::from(fields.collect::<Vec<&str>>().join(" "));
+                    report.maps.push(MemoryMap {
+                        start,
+                        end,
+                        offset,
+                        path,
+                    });
+                }
+                Some("stack") => {
+                    let index = parse(fields.next(), &line, dec)?;
+                    if index != report.stacks.len() {
+                        return Err(invalid(&line));
+                    }
+                    let stack = fields
+                        .map(|field| parse(Some(field), &line, hex))
+                        .collect::<std::io::Result<Vec<u64>>>()?;
+                    report.stacks.push(stack);
+                }
+                Some("trim") => {
+                    report.trim_frames = true;
+                    report.max_depth = parse(fields.next(), &line, dec)?;
+                }
+                Some("thread") => {
+                    let id = parse(fields.next(), &line, |field| field.parse().ok())?;
+                    // the name is the rest of the line, which could contain spaces
+                    let name = fields.collect::<Vec<&str>>().join(" ");
+                    report.threads.push(Thread {
+                        id,
+                        name: if name.is_empty() { None } else { Some(name) },
+                    });
+                }
+                Some("tag") => {
+                    let id = parse(fields.next(), &line, |field| field.parse().ok())?;
+                    let name = fields.collect::<Vec<&str>>().join(" ");
+                    report.tags.push((id, name));
+                }
+                Some("live") => {
+                    let stack = parse(fields.next(), &line, dec)?;
+                    let thread = parse(fields.next(), &line, |field| field.parse().ok())?;
+                    let tag = parse(fields.next(), &line, |field| field.parse().ok())?;
+                    let mut next = || parse(fields.next(), &line, dec);
+                    let stats = AllocStats {
+                        live_bytes: next()?,
+                        live_objects: next()?,
+                        total_bytes: next()?,
+                        total_objects: next()?,
+                        reallocs: next()?,
+                        zeroed_bytes: next()?,
+                        zeroed_objects: next()?,
+                    };
+                    report.live.push(((stack, thread, tag), stats));
+                }
+                Some("freed") => {
+                    let mut next = || parse(fields.next(), &line, dec);
+                    let alloc = next()?;
+                    let free = next()?;
+                    let stats = FreedStats {
+                        bytes: next()?,
+                        objects: next()?,
+                    };
+                    report.freed.push(((alloc, free), stats));
+                }
+                Some("lifetime") => {
+                    let mut next = || parse(fields.next(), &line, dec);
+                    let stack = next()?;
+                    let mut lifetimes = Lifetimes::default();
+                    for count in lifetimes.freed.iter_mut().chain(lifetimes.live.iter_mut()) {
+                        *count = next()?;
+                    }
+                    report.lifetimes.push((stack, lifetimes));
+                }
+                Some("size") => {
+                    let stack = parse(fields.next(), &line, dec)?;
+                    let mut sizes = SizeHistogram::default();
+                    for field in fields {
+                        let (class, objects) = parse(Some(field), &line, |field| {
+                            let (class, objects) = field.split_at(field.find(':')?);
+                            Some((dec(class)?, dec(&objects[1..])?))
+                        })?;
+                        sizes.add_class(class, objects);
+                    }
+                    report.sizes.push((stack, sizes));
+                }
+                Some("") => {}
+                _ => return Err(invalid(&line)),
+            }
+        }
+
+        let stacks = report.stacks.len();
+        let referenced = report
+            .live
+            .iter()
+            .map(|((stack, _, _), _)| *stack)
+            .chain(report.freed.iter().flat_map(|((alloc, free), _)| vec![*alloc, *free]))
+            .chain(report.lifetimes.iter().map(|(stack, _)| *stack))
+            .chain(report.sizes.iter().map(|(stack, _)| *stack));
+        if referenced.into_iter().any(|stack| stack >= stacks) {
+            return Err(Error::new(ErrorKind::InvalidData, "unknown stack"));
+        }
+
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::TRUNCATED;
+
+    #[test]
+    fn write_and_read() {
+        let mut lifetimes = Lifetimes::default();
+        lifetimes.add_freed(500, 3);
+        lifetimes.add_live(2_000_000_000, 1);
+
+        let mut sizes = SizeHistogram::default();
+        sizes.add(24, 2);
+        sizes.add(4000, 1);
+
+        let report = RawReport {
+            maps: vec![
+                MemoryMap {
+                    start: 0x5555_0000,
+                    end: 0x5556_0000,
+                    offset: 0x1000,
+                    path: PathBuf::from("/opt/my app/bin"),
+                },
+                MemoryMap {
+                    start: 0x7fff_0000,
+                    end: 0x7fff_1000,
+                    offset: 0,
+                    path: PathBuf::new(),
+                },
+            ],
+            stacks: vec![vec![0x5555_1234, 0x5555_5678], vec![0x5555_1234, TRUNCATED]],
+            trim_frames: true,
+            max_depth: 128,
+            threads: vec![
+                Thread {
+                    id: 1,
+                    name: Some("main".to_owned()),
+                },
+                Thread {
+                    id: 2,
+                    name: Some("worker pool 1".to_owned()),
+                },
+                Thread { id: 3, name: None },
+            ],
+            tags: vec![(1, "request:upload".to_owned()), (2, "two words".to_owned())],
+            live: vec![
+                (
+                    (0, 1, 0),
+                    AllocStats {
+                        live_bytes: 100,
+                        live_objects: 2,
+                        total_bytes: 300,
+                        total_objects: 5,
+                        reallocs: 1,
+                        zeroed_bytes: 50,
+                        zeroed_objects: 1,
+                    },
+                ),
+                ((1, 2, 2), AllocStats::default()),
+            ],
+            freed: vec![(
+                (0, 1),
+                FreedStats {
+                    bytes: 200,
+                    objects: 3,
+                },
+            )],
+            lifetimes: vec![(0, lifetimes)],
+            sizes: vec![(0, sizes)],
+            stats: Stats {
+                events: 10,
+                dropped_events: 1,
+                queue_depth: 2,
+                tracked_allocations: 3,
+                stacks: 2,
+                collector_bytes: 4096,
+                unwind_time: Duration::from_nanos(12345),
+            },
+        };
+
+        let mut buf = Vec::new();
+        report.write(&mut buf).unwrap();
+
+        assert_eq!(RawReport::read(&buf[..]).unwrap(), report);
+    }
+
+    #[test]
+    fn read_unknown_stack() {
+        let raw = format!("{}\nstack 0 1234\nfreed 0 1 8 1\n", HEADER);
+
+        assert!(RawReport::read(raw.as_bytes()).is_err());
+    }
+}
@@ -2,9 +2,217 @@ use crate::frame::Frames;
 use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
 use crate::collector::CollectorClient;
+use crate::lifetime::Lifetimes;
+use crate::maps::MemoryMap;
+use crate::sampler::{self, Objects};
+use crate::sizes::{SizeClasses, SizeHistogram};
+use crate::stats::Stats;
+use std::ops::AddAssign;
 
+/// Statistics of the allocations made from one call stack.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AllocStats {
+    /// Bytes allocated and not freed yet.
+    pub live_bytes: usize,
+
+    /// Number of allocations not freed yet.
+    pub live_objects: usize,
+
+    /// Bytes allocated since the collector started, including the freed ones.
+    pub total_bytes: usize,
+
+    /// Number of allocations since the collector started, including the freed ones.
+    pub total_objects: usize,
+
+    /// Number of reallocations called from this stack.
+    pub reallocs: usize,
+
+    /// Bytes allocated through `alloc_zeroed`, included in `total_bytes`.
+    pub zeroed_bytes: usize,
+
+    /// Number of allocations through `alloc_zeroed`, included in `total_objects`.
+    pub zeroed_objects: usize,
+}
+
+impl AllocStats {
+    /// Whether any allocation from this stack asked for zeroed memory.
+    pub fn zeroed(&self) -> bool {
+        self.zeroed_objects > 0
+    }
+}
+
+impl AddAssign for AllocStats {
+    fn add_assign(&mut self, other: AllocStats) {
+        self.live_bytes += other.live_bytes;
+        self.live_objects += other.live_objects;
+        self.total_bytes += other.total_bytes;
+        self.total_objects += other.total_objects;
+        self.reallocs += other.reallocs;
+        self.zeroed_bytes += other.zeroed_bytes;
+        self.zeroed_objects += other.zeroed_objects;
+    }
+}
+
+impl Objects for AllocStats {
+    fn round_objects(self) -> Self {
+        AllocStats {
+            live_objects: sampler::objects(self.live_objects),
+            total_objects: sampler::objects(self.total_objects),
+            reallocs: sampler::objects(self.reallocs),
+            zeroed_objects: sampler::objects(self.zeroed_objects),
+            ..self
+        }
+    }
+}
+
+impl Display for AllocStats {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "live: {} bytes in {} objects, total: {} bytes in {} objects, reallocs: {}",
+            self.live_bytes, self.live_objects, self.total_bytes, self.total_objects, self.reallocs
+        )?;
+        if self.zeroed() {
+            write!(
+                f,
+                ", zeroed: {} bytes in {} objects",
+                self.zeroed_bytes, self.zeroed_objects
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Statistics of the allocations made from one stack and freed from another.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FreedStats {
+    /// Bytes freed.
+    pub bytes: usize,
+
+    /// Number of freed allocations.
+    pub objects: usize,
+}
+
+impl AddAssign for FreedStats {
+    fn add_assign(&mut self, other: FreedStats) {
+        self.bytes += other.bytes;
+        self.objects += other.objects;
+    }
+}
+
+impl Objects for FreedStats {
+    fn round_objects(self) -> Self {
+        FreedStats {
+            objects: sampler::objects(self.objects),
+            ..self
+        }
+    }
+}
+
+impl Display for FreedStats {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "freed: {} bytes in {} objects", self.bytes, self.objects)
+    }
+}
+
+/// A thread which made allocations. Threads are numbered from 1 in the order they first allocate,
+/// and are named by `std::thread::Builder::name`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Thread {
+    pub id: u32,
+    pub name: Option<String>,
+}
+
+impl Display for Thread {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{}", name),
+            None => write!(f, "thread {}", self.id),
+        }
+    }
+}
+
+/// The key of the freed view: where the memory was allocated and where it was freed.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FreedStack {
+    pub alloc: Frames,
+    pub free: Frames,
+}
+
+/// Metric selects which value of a report is rendered. `FreedBytes` and `FreedObjects` render the
+/// freed view, and the others render the live view.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Metric {
+    LiveBytes,
+    LiveObjects,
+    TotalBytes,
+    TotalObjects,
+    Reallocs,
+    ZeroedBytes,
+    ZeroedObjects,
+    FreedBytes,
+    FreedObjects,
+}
+
+impl Metric {
+    pub(crate) fn value(self, stats: &AllocStats) -> usize {
+        match self {
+            Metric::LiveBytes => stats.live_bytes,
+            Metric::LiveObjects => stats.live_objects,
+            Metric::TotalBytes => stats.total_bytes,
+            Metric::TotalObjects => stats.total_objects,
+            Metric::Reallocs => stats.reallocs,
+            Metric::ZeroedBytes => stats.zeroed_bytes,
+            Metric::ZeroedObjects => stats.zeroed_objects,
+            Metric::FreedBytes | Metric::FreedObjects => 0,
+        }
+    }
+
+    fn freed_value(self, stats: &FreedStats) -> usize {
+        match self {
+            Metric::FreedBytes => stats.bytes,
+            Metric::FreedObjects => stats.objects,
+            _ => 0,
+        }
+    }
+
+    /// Whether this metric is taken from the freed view.
+    pub fn is_freed(self) -> bool {
+        matches!(self, Metric::FreedBytes | Metric::FreedObjects)
+    }
+
+    /// The unit of the value, used as the count name of flamegraph.
+    pub fn unit(self) -> &'static str {
+        match self {
+            Metric::LiveBytes | Metric::TotalBytes | Metric::ZeroedBytes | Metric::FreedBytes => {
+                "bytes"
+            }
+            Metric::LiveObjects
+            | Metric::TotalObjects
+            | Metric::ZeroedObjects
+            | Metric::FreedObjects => "objects",
+            Metric::Reallocs => "reallocs",
+        }
+    }
+}
+
+/// Report has two views of the heap. The live view is keyed by allocation stack, and records the
+/// memory still held together with the totals allocated from that stack. It is also split by the
+/// thread which made the allocations, in `threads`, and by the tag of their scope, in `tags`. The
+/// freed view is keyed by the pair of allocation and free stack, and records the memory freed
+/// there. The lifetimes and the sizes of the allocations are keyed by allocation stack. The stats
+/// of the profiler and the executable regions of the profiled process at the time of the report are
+/// kept along.
 pub struct Report {
-    pub data: HashMap<Frames, usize>,
+    pub live: HashMap<Frames, AllocStats>,
+    pub threads: HashMap<Thread, HashMap<Frames, AllocStats>>,
+    pub tags: HashMap<String, HashMap<Frames, AllocStats>>,
+    pub freed: HashMap<FreedStack, FreedStats>,
+    pub lifetimes: HashMap<Frames, Lifetimes>,
+    pub sizes: HashMap<Frames, SizeHistogram>,
+    pub stats: Stats,
+    pub maps: Vec<MemoryMap>,
 }
 
 pub struct ReportReader<'a> {
@@ -35,9 +243,147 @@ impl<'a> ReportReader<'a> {
     }
 }
 
+impl Report {
+    /// The sizes of the allocations from all stacks.
+    pub fn size_histogram(&self) -> SizeHistogram {
+        let mut histogram = SizeHistogram::default();
+        for sizes in self.sizes.values() {
+            histogram += sizes.clone();
+        }
+
+        histogram
+    }
+
+    /// The live view of every thread, summed over its stacks.
+    pub fn thread_stats(&self) -> HashMap<Thread, AllocStats> {
+        self.threads
+            .iter()
+            .map(|(thread, live)| (thread.clone(), sum(live)))
+            .collect()
+    }
+
+    /// The live view of every tag, summed over its stacks.
+    pub fn tag_stats(&self) -> HashMap<String, AllocStats> {
+        self.tags
+            .iter()
+            .map(|(tag, live)| (tag.clone(), sum(live)))
+            .collect()
+    }
+
+    /// Gives a report of the allocations made by the threads matching `filter`. Only the live view
+    /// is kept by thread, so the other views of the result are empty.
+    pub fn filter_threads<F>(&self, filter: F) -> Report
+    where
+        F: Fn(&Thread) -> bool,
+    {
+        let threads: HashMap<Thread, HashMap<Frames, AllocStats>> = self
+            .threads
+            .iter()
+            .filter(|(thread, _)| filter(thread))
+            .map(|(thread, live)| (thread.clone(), live.clone()))
+            .collect();
+
+        let mut live: HashMap<Frames, AllocStats> = HashMap::new();
+        for (frames, stats) in threads.values().flatten() {
+            *live.entry(frames.clone()).or_default() += *stats;
+        }
+
+        Report {
+            live,
+            threads,
+            tags: HashMap::new(),
+            freed: HashMap::new(),
+            lifetimes: HashMap::new(),
+            sizes: HashMap::new(),
+            stats: self.stats,
+            maps: self.maps.clone(),
+        }
+    }
+
+    /// Gives a report of the allocations tagged by the scopes matching `filter`. Only the live
+    /// view is kept by tag, so the other views of the result are empty.
+    pub fn filter_tags<F>(&self, filter: F) -> Report
+    where
+        F: Fn(&str) -> bool,
+    {
+        let tags: HashMap<String, HashMap<Frames, AllocStats>> = self
+            .tags
+            .iter()
+            .filter(|(tag, _)| filter(tag))
+            .map(|(tag, live)| (tag.clone(), live.clone()))
+            .collect();
+
+        let mut live: HashMap<Frames, AllocStats> = HashMap::new();
+        for (frames, stats) in tags.values().flatten() {
+            *live.entry(frames.clone()).or_default() += *stats;
+        }
+
+        Report {
+            live,
+            threads: HashMap::new(),
+            tags,
+            freed: HashMap::new(),
+            lifetimes: HashMap::new(),
+            sizes: HashMap::new(),
+            stats: self.stats,
+            maps: self.maps.clone(),
+        }
+    }
+}
+
+fn sum(live: &HashMap<Frames, AllocStats>) -> AllocStats {
+    let mut total = AllocStats::default();
+    for stats in live.values() {
+        total += *stats;
+    }
+
+    total
+}
+
 impl Display for Report {
     fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
-        for (key, val) in self.data.iter() {
+        writeln!(f, "STATS: {}", self.stats)?;
+
+        // stacks which only reallocate have no allocation of their own, see `Metric::Reallocs`
+        writeln!(f, "LIVE:")?;
+        for (key, val) in self
+            .live
+            .iter()
+            .filter(|(_, val)| val.live_objects > 0 || val.total_objects > 0)
+        {
+            write!(f, "{} {}", key, val)?;
+            writeln!(f)?;
+        }
+
+        writeln!(f, "THREADS:")?;
+        for (key, val) in self.thread_stats().iter() {
+            writeln!(f, "{} ({}) {}", key, key.id, val)?;
+        }
+
+        writeln!(f, "TAGS:")?;
+        for (key, val) in self.tag_stats().iter() {
+            writeln!(f, "{} {}", key, val)?;
+        }
+
+        writeln!(f, "FREED:")?;
+        for (key, val) in self.freed.iter() {
+            write!(f, "{} FREED BY: {} {}", key.alloc, key.free, val)?;
+            writeln!(f)?;
+        }
+
+        writeln!(f, "LIFETIMES:")?;
+        for (key, val) in self.lifetimes.iter() {
+            write!(f, "{} {}", key, val)?;
+            writeln!(f)?;
+        }
+
+        let histogram = self.size_histogram();
+        write!(f, "SIZES: ")?;
+        histogram.write_buckets(f, SizeClasses::PowerOfTwo)?;
+        write!(f, "\nSIZE CLASSES: ")?;
+        histogram.write_buckets(f, SizeClasses::Jemalloc)?;
+        writeln!(f)?;
+        for (key, val) in self.sizes.iter() {
             write!(f, "{} {}", key, val)?;
             writeln!(f)?;
         }
@@ -46,44 +392,151 @@ impl Display for Report {
     }
 }
 
+/// Escapes a frame for the folded format. `;` separates frames, so it is replaced with `:`, as in
+/// `[u8: 32]`. Line breaks and tabs become spaces, and spaces are kept: the count is split from
+/// the last space of a line, which is how inferno and speedscope parse it.
+fn escape(frame: &str) -> String {
+    frame
+        .chars()
+        .map(|c| match c {
+            ';' => ':',
+            '\n' | '\r' | '\t' => ' ',
+            c => c,
+        })
+        .collect()
+}
+
+/// Folds stacks into the `root;..;leaf` form. Later stacks are drawn on top of the former ones.
+pub(crate) fn fold(stacks: &[&Frames]) -> String {
+    let mut line = String::new();
+
+    for (index, frames) in stacks.iter().enumerate() {
+        if index > 0 {
+            line.push_str("[freed by];");
+        }
+        for frame in frames.frames.iter().rev().filter(|frame| !frame.is_empty()) {
+            for symbol in frame.iter().rev() {
+                line.push_str(&escape(&symbol.to_string()));
+                line.push('/');
+            }
+            line.pop().unwrap_or_default();
+            line.push(';');
+        }
+    }
+    line.pop().unwrap_or_default();
+
+    line
+}
+
 mod flamegraph {
     use super::*;
+    use crate::error::{Error, Result};
     use std::io::Write;
 
     impl Report {
-        pub fn flamegraph<W>(&self, writer: W)
+        /// Folds every stack with its value. With `by_thread`, the live stacks are rooted at the
+        /// name of their thread, so the threads of a pool sharing a name are drawn together.
+        fn lines(&self, metric: Metric, by_thread: bool) -> Vec<String> {
+            let stacks: Vec<(Option<&Thread>, Vec<&Frames>, usize)> = if metric.is_freed() {
+                self.freed
+                    .iter()
+                    .map(|(key, stats)| {
+                        (None, vec![&key.alloc, &key.free], metric.freed_value(stats))
+                    })
+                    .collect()
+            } else if by_thread {
+                self.threads
+                    .iter()
+                    .flat_map(|(thread, live)| {
+                        live.iter()
+                            .map(move |(key, stats)| (Some(thread), vec![key], metric.value(stats)))
+                    })
+                    .collect()
+            } else {
+                self.live
+                    .iter()
+                    .map(|(key, stats)| (None, vec![key], metric.value(stats)))
+                    .collect()
+            };
+
+            stacks
+                .into_iter()
+                .filter(|(_, _, value)| *value > 0)
+                // the free stack is drawn on top of the allocation stack
+                .map(|(thread, stacks, value)| match thread {
+                    Some(thread) => {
+                        format!("{};{} {}", escape(&thread.to_string()), fold(&stacks), value)
+                    }
+                    None => format!("{} {}", fold(&stacks), value),
+                })
+                .collect()
+        }
+
+        fn write_lines<W>(lines: Vec<String>, mut writer: W) -> std::io::Result<()>
         where
             W: Write,
         {
-            use inferno::flamegraph;
+            for line in lines {
+                writeln!(writer, "{}", line)?;
+            }
 
-            let lines: Vec<String> = self
-                .data
-                .iter()
-                .map(|(key, value)| {
-                    let mut line = String::new();
-
-                    for frame in key.frames.iter().rev() {
-                        for symbol in frame.iter().rev() {
-                            line.push_str(&format!("{}/", symbol));
-                        }
-                        line.pop().unwrap_or_default();
-                        line.push(';');
-                    }
+            Ok(())
+        }
 
-                    line.pop().unwrap_or_default();
-                    line.push_str(&format!(" {}", value));
+        /// Writes the stacks in the collapsed format, one `root;..;leaf count` line for every stack,
+        /// which can be read by inferno, speedscope and FlameGraph scripts.
+        pub fn write_folded<W>(&self, metric: Metric, writer: W) -> std::io::Result<()>
+        where
+            W: Write,
+        {
+            Self::write_lines(self.lines(metric, false), writer)
+        }
 
-                    line
-                })
-                .collect();
-            if !lines.is_empty() {
-                let mut options = flamegraph::Options::default();
-                options.hash = true;
-                options.count_name = "bytes".to_owned();
+        /// Like `write_folded`, with the name of the allocating thread as the root frame of every
+        /// live stack. The freed view is not kept by thread, so it has no such frame.
+        pub fn write_folded_by_thread<W>(&self, metric: Metric, writer: W) -> std::io::Result<()>
+        where
+            W: Write,
+        {
+            Self::write_lines(self.lines(metric, true), writer)
+        }
+
+        /// Renders the stacks as a flamegraph. Fails with `Error::NoStacks` if no stack has a value
+        /// of `metric`.
+        pub fn flamegraph<W>(&self, metric: Metric, writer: W) -> Result<()>
+        where
+            W: Write,
+        {
+            Self::render(self.lines(metric, false), metric, writer)
+        }
+
+        /// Like `flamegraph`, with the name of the allocating thread as the root frame of every
+        /// live stack.
+        pub fn flamegraph_by_thread<W>(&self, metric: Metric, writer: W) -> Result<()>
+        where
+            W: Write,
+        {
+            Self::render(self.lines(metric, true), metric, writer)
+        }
 
-                flamegraph::from_lines(&mut options, lines.iter().map(|s| &**s), writer).unwrap(); // TODO: handle this error
+        fn render<W>(lines: Vec<String>, metric: Metric, writer: W) -> Result<()>
+        where
+            W: Write,
+        {
+            use inferno::flamegraph;
+
+            if lines.is_empty() {
+                return Err(Error::NoStacks);
             }
+
+            let mut options = flamegraph::Options {
+                hash: true,
+                count_name: metric.unit().to_owned(),
+                ..Default::default()
+            };
+            flamegraph::from_lines(&mut options, lines.iter().map(|s| &**s), writer)?;
+
+            Ok(())
         }
     }
 }
@@ -0,0 +1,279 @@
+use crate::frame::{resolve_addr, Frames, Symbol, TRUNCATED};
+use crate::lifetime::Lifetimes;
+use crate::sizes::SizeHistogram;
+use crate::raw::RawReport;
+use crate::report::{AllocStats, FreedStack, FreedStats, Report, Thread};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use addr2line::gimli;
+use object::{Object, ObjectSegment};
+
+type Reader<'data> = gimli::EndianSlice<'data, gimli::RunTimeEndian>;
+
+/// The debug info of one module, borrowed from the content of its file.
+struct Module<'data> {
+    context: Option<addr2line::Context<Reader<'data>>>,
+
+    /// The file offset, size and address of every loaded segment.
+    segments: Vec<(u64, u64, u64)>,
+
+    /// Symbols sorted by address, used when there is no DWARF.
+    symbols: Vec<(u64, String)>,
+}
+
+impl<'data> Module<'data> {
+    fn parse(data: &'data [u8]) -> Option<Module<'data>> {
+        let file = object::File::parse(data).ok()?;
+
+        let endian = if file.is_little_endian() {
+            gimli::RunTimeEndian::Little
+        } else {
+            gimli::RunTimeEndian::Big
+        };
+        let load_section = |id: gimli::SectionId| -> Result<Reader<'data>, gimli::Error> {
+            let data = object::Object::section_by_name(&file, id.name())
+                .and_then(|section| object::ObjectSection::data(&section).ok())
+                .unwrap_or(&[]);
+            Ok(Reader::new(data, endian))
+        };
+        let context = gimli::Dwarf::load(load_section)
+            .ok()
+            .and_then(|dwarf| addr2line::Context::from_dwarf(dwarf).ok());
+
+        let segments = file
+            .segments()
+            .map(|segment| {
+                let (offset, size) = segment.file_range();
+                (offset, size, segment.address())
+            })
+            .collect();
+
+        let mut symbols: Vec<(u64, String)> = file
+            .symbol_map()
+            .symbols()
+            .iter()
+            .map(|symbol| (symbol.address(), symbol.name().to_owned()))
+            .collect();
+        symbols.sort();
+
+        Some(Module {
+            context,
+            segments,
+            symbols,
+        })
+    }
+
+    /// Translates an offset in the file into the address in the debug info.
+    fn address(&self, offset: u64) -> Option<u64> {
+        self.segments
+            .iter()
+            .find(|(start, size, _)| *start <= offset && offset < start + size)
+            .map(|(start, _, address)| offset - start + address)
+    }
+
+    fn symbols(&self, addr: u64) -> Vec<Symbol> {
+        let mut symbols = Vec::new();
+
+        if let Some(context) = &self.context {
+            if let Ok(mut frames) = context.find_frames(addr).skip_all_loads() {
+                while let Ok(Some(frame)) = frames.next() {
+                    let name = frame
+                        .function
+                        .as_ref()
+                        .and_then(|function| function.raw_name().ok())
+                        .map(|name| name.as_bytes().to_vec());
+                    let location = frame.location.as_ref();
+                    symbols.push(Symbol {
+                        name,
+                        addr: None,
+                        lineno: location.and_then(|location| location.line),
+                        filename: location
+                            .and_then(|location| location.file)
+                            .map(PathBuf::from),
+                    });
+                }
+            }
+        }
+
+        if symbols.iter().all(|symbol| symbol.name.is_none()) {
+            let index = self.symbols.partition_point(|(start, _)| *start <= addr);
+            if index > 0 {
+                symbols = vec![Symbol {
+                    name: Some(self.symbols[index - 1].1.as_bytes().to_vec()),
+                    addr: None,
+                    lineno: None,
+                    filename: None,
+                }];
+            }
+        }
+
+        symbols
+    }
+}
+
+/// Resolver symbolizes a `RawReport` away from the profiled process. The modules are read from
+/// the paths recorded in the report, unless a separate file carrying the debug info is given.
+#[derive(Default)]
+pub struct Resolver {
+    debug_files: HashMap<PathBuf, PathBuf>,
+}
+
+impl Resolver {
+    pub fn new() -> Self {
+        Resolver::default()
+    }
+
+    /// Reads the debug info of `module` from `debug_file`. The module is matched with either its
+    /// full path or its file name.
+    pub fn debug_file<P, Q>(mut self, module: P, debug_file: Q) -> Self
+    where
+        P: Into<PathBuf>,
+        Q: Into<PathBuf>,
+    {
+        self.debug_files.insert(module.into(), debug_file.into());
+        self
+    }
+
+    fn debug_path(&self, module: &Path) -> PathBuf {
+        let by_name = module
+            .file_name()
+            .and_then(|name| self.debug_files.get(Path::new(name)));
+
+        self.debug_files
+            .get(module)
+            .or(by_name)
+            .cloned()
+            .unwrap_or_else(|| module.to_owned())
+    }
+
+    /// Resolves every address of `raw`. Every module is read once, for all of its addresses.
+    fn symbols(&self, raw: &RawReport) -> HashMap<u64, Vec<Symbol>> {
+        let mut symbols = HashMap::new();
+
+        let mut addresses: HashMap<usize, Vec<u64>> = HashMap::new();
+        for addr in raw.stacks.iter().flatten() {
+            if *addr == TRUNCATED {
+                symbols.insert(*addr, resolve_addr(*addr));
+            } else if let Some(index) = raw.maps.iter().position(|map| map.contains(*addr)) {
+                addresses.entry(index).or_default().push(*addr);
+            }
+        }
+
+        for (index, addresses) in addresses {
+            let map = &raw.maps[index];
+            let data = match std::fs::read(self.debug_path(&map.path)) {
+                Ok(data) => data,
+                Err(_) => continue,
+            };
+            let module = match Module::parse(&data) {
+                Some(module) => module,
+                None => continue,
+            };
+
+            for addr in addresses {
+                // the addresses are return addresses, so look up the call instruction before them
+                if let Some(address) = module.address(addr - map.start + map.offset) {
+                    symbols.insert(addr, module.symbols(address.saturating_sub(1)));
+                }
+            }
+        }
+
+        symbols
+    }
+
+    /// Resolves all stacks of `raw`. Frames which cannot be resolved have no symbols. The frames
+    /// of the profiler are trimmed if the report asks for it.
+    pub fn resolve(&self, raw: &RawReport) -> Report {
+        let symbols = self.symbols(raw);
+        let stacks: Vec<Frames> = raw
+            .stacks
+            .iter()
+            .map(|stack| {
+                let mut frames = Frames {
+                    frames: stack
+                        .iter()
+                        .map(|addr| symbols.get(addr).cloned().unwrap_or_default())
+                        .collect(),
+                    addresses: stack.clone(),
+                };
+                if raw.trim_frames {
+                    frames.trim_profiler_frames(raw.max_depth);
+                }
+
+                frames
+            })
+            .collect();
+
+        let names: HashMap<u32, &Thread> =
+            raw.threads.iter().map(|thread| (thread.id, thread)).collect();
+
+        let tag_names: HashMap<u32, &String> = raw.tags.iter().map(|(id, name)| (*id, name)).collect();
+
+        let mut live: HashMap<Frames, AllocStats> = HashMap::new();
+        let mut threads: HashMap<Thread, HashMap<Frames, AllocStats>> = HashMap::new();
+        let mut tags: HashMap<String, HashMap<Frames, AllocStats>> = HashMap::new();
+        for ((stack, id, tag), stats) in raw.live.iter() {
+            *live.entry(stacks[*stack].clone()).or_default() += *stats;
+
+            let thread = names.get(id).map(|thread| (*thread).clone()).unwrap_or(Thread {
+                id: *id,
+                name: None,
+            });
+            *threads
+                .entry(thread)
+                .or_default()
+                .entry(stacks[*stack].clone())
+                .or_default() += *stats;
+
+            if *tag != 0 {
+                let tag = tag_names
+                    .get(tag)
+                    .map(|name| (*name).clone())
+                    .unwrap_or_else(|| format!("tag {}", tag));
+                *tags
+                    .entry(tag)
+                    .or_default()
+                    .entry(stacks[*stack].clone())
+                    .or_default() += *stats;
+            }
+        }
+
+        let mut freed: HashMap<FreedStack, FreedStats> = HashMap::new();
+        for ((alloc, free), stats) in raw.freed.iter() {
+            let stack = FreedStack {
+                alloc: stacks[*alloc].clone(),
+                free: stacks[*free].clone(),
+            };
+            *freed.entry(stack).or_default() += *stats;
+        }
+
+        let mut lifetimes: HashMap<Frames, Lifetimes> = HashMap::new();
+        for (stack, stats) in raw.lifetimes.iter() {
+            *lifetimes.entry(stacks[*stack].clone()).or_default() += *stats;
+        }
+
+        let mut sizes: HashMap<Frames, SizeHistogram> = HashMap::new();
+        for (stack, histogram) in raw.sizes.iter() {
+            *sizes.entry(stacks[*stack].clone()).or_default() += histogram.clone();
+        }
+
+        Report {
+            live,
+            threads,
+            tags,
+            freed,
+            lifetimes,
+            sizes,
+            stats: raw.stats,
+            maps: raw.maps.clone(),
+        }
+    }
+}
+
+impl RawReport {
+    /// Resolves the report with the modules at the paths recorded in it.
+    pub fn resolve(&self) -> Report {
+        Resolver::new().resolve(self)
+    }
+}
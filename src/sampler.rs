@@ -0,0 +1,207 @@
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Number of slots in the recorded address filter. Must be a power of two.
+const FILTER_SLOTS: usize = 1 << 16;
+
+/// The collector counts objects in units of 1/`OBJECT_UNIT` object, so the fractional weights of
+/// samples add up without being rounded one by one. Counts are rounded to whole objects by `Objects`
+/// when a report is built.
+pub(crate) const OBJECT_UNIT: usize = 1 << 16;
+
+thread_local! {
+    static SAMPLER: Sampler = Sampler::new();
+}
+
+/// Sampler decides which allocations are recorded when a sample interval is configured. It counts
+/// down the allocated bytes and picks the allocation which crosses zero, then draws the next
+/// countdown from an exponential distribution with the interval as its mean. This makes every byte
+/// equally likely to be sampled, like `lg_prof_sample` in jemalloc.
+struct Sampler {
+    bytes_until_sample: Cell<usize>,
+    rng: Cell<u64>,
+}
+
+impl Sampler {
+    fn new() -> Self {
+        Sampler {
+            bytes_until_sample: Cell::new(0),
+            rng: Cell::new(0),
+        }
+    }
+
+    fn sample(&self, size: usize, interval: usize) -> bool {
+        if self.rng.get() == 0 {
+            self.seed();
+            self.bytes_until_sample.set(self.next_interval(interval));
+        }
+
+        let remaining = self.bytes_until_sample.get();
+        if size < remaining {
+            self.bytes_until_sample.set(remaining - size);
+            false
+        } else {
+            self.bytes_until_sample.set(self.next_interval(interval));
+            true
+        }
+    }
+
+    fn seed(&self) {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|duration| duration.subsec_nanos() as u64)
+            .unwrap_or(0);
+        let seed = (self as *const Sampler as u64) ^ nanos.rotate_left(32);
+
+        self.rng.set(seed | 1);
+    }
+
+    fn next_interval(&self, interval: usize) -> usize {
+        // xorshift64, which never yields zero from a non-zero state
+        let mut x = self.rng.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rng.set(x);
+
+        // uniform in (0, 1]
+        let u = ((x >> 11) + 1) as f64 / (1u64 << 53) as f64;
+        (-u.ln() * interval as f64) as usize + 1
+    }
+}
+
+/// Returns whether an allocation of `size` bytes on the current thread should be recorded.
+pub(crate) fn sample(size: usize, interval: usize) -> bool {
+    SAMPLER
+        .try_with(|sampler| sampler.sample(size, interval))
+        .unwrap_or(false)
+}
+
+/// Returns the factor to scale a sampled allocation of `size` bytes with, so that the sum over all
+/// sampled allocations is an unbiased estimation of the real value.
+pub(crate) fn weight(size: usize, interval: usize) -> f64 {
+    if interval == 0 {
+        1.0
+    } else {
+        let probability = 1.0 - (-(size as f64) / interval as f64).exp();
+        1.0 / probability
+    }
+}
+
+/// Returns the estimated bytes and number of allocations, in `OBJECT_UNIT`s, represented by a
+/// sampled allocation of `size` bytes.
+pub(crate) fn scale(size: usize, interval: usize) -> (usize, usize) {
+    let weight = weight(size, interval);
+    (
+        (size as f64 * weight).round() as usize,
+        (OBJECT_UNIT as f64 * weight).round() as usize,
+    )
+}
+
+/// Rounds a count of `OBJECT_UNIT`s to whole objects.
+pub(crate) fn objects(units: usize) -> usize {
+    (units + OBJECT_UNIT / 2) / OBJECT_UNIT
+}
+
+/// Values counting objects in `OBJECT_UNIT`s, which are converted to whole objects for reports.
+pub(crate) trait Objects {
+    fn round_objects(self) -> Self;
+}
+
+/// AddressFilter remembers which addresses may have been recorded, so the deallocation of an
+/// address which has not been, as it was not sampled or tracking was paused, can be skipped without
+/// asking the collector. It is a counting filter with a single hash: `contains` could give false
+/// positives but never false negatives. Slots are incremented on the allocating thread before the
+/// event is sent, and decremented by the collector once it forgets the address.
+pub(crate) struct AddressFilter {
+    slots: [AtomicU32; FILTER_SLOTS],
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_SLOT: AtomicU32 = AtomicU32::new(0);
+
+pub(crate) static RECORDED: AddressFilter = AddressFilter {
+    slots: [EMPTY_SLOT; FILTER_SLOTS],
+};
+
+impl AddressFilter {
+    fn slot(&self, addr: u64) -> &AtomicU32 {
+        let hash = (addr >> 4).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        &self.slots[(hash >> 48) as usize & (FILTER_SLOTS - 1)]
+    }
+
+    pub fn insert(&self, addr: u64) {
+        self.slot(addr).fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn remove(&self, addr: u64) {
+        self.slot(addr).fetch_sub(1, Ordering::SeqCst);
+    }
+
+    pub fn contains(&self, addr: u64) -> bool {
+        self.slot(addr).load(Ordering::SeqCst) > 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Estimates the bytes and objects of `count` allocations of `size` bytes from a sample.
+    fn estimate(size: usize, count: usize, interval: usize) -> (usize, usize) {
+        let sampler = Sampler::new();
+        sampler.rng.set(0x2545_F491_4F6C_DD1D);
+        sampler.bytes_until_sample.set(sampler.next_interval(interval));
+
+        let (mut bytes, mut units) = (0, 0);
+        for _ in 0..count {
+            if sampler.sample(size, interval) {
+                let (sample_bytes, sample_units) = scale(size, interval);
+                bytes += sample_bytes;
+                units += sample_units;
+            }
+        }
+
+        (bytes, objects(units))
+    }
+
+    fn assert_close(estimate: usize, expected: usize) {
+        let error = (estimate as f64 - expected as f64).abs() / expected as f64;
+        assert!(error < 0.02, "estimated {}, expected {}", estimate, expected);
+    }
+
+    #[test]
+    fn unsampled() {
+        assert_eq!(scale(100, 0), (100, OBJECT_UNIT));
+        assert_eq!(estimate(100, 1000, 0), (100_000, 1000));
+    }
+
+    #[test]
+    fn estimates_interval_sized_allocations() {
+        let (bytes, objects) = estimate(4096, 100_000, 4096);
+        assert_close(bytes, 4096 * 100_000);
+        assert_close(objects, 100_000);
+    }
+
+    #[test]
+    fn estimates_small_allocations() {
+        let (bytes, objects) = estimate(16, 2_000_000, 512);
+        assert_close(bytes, 16 * 2_000_000);
+        assert_close(objects, 2_000_000);
+    }
+
+    #[test]
+    fn estimates_large_allocations() {
+        let (bytes, objects) = estimate(1 << 20, 10_000, 4096);
+        assert_eq!(bytes, 10_000 << 20);
+        assert_eq!(objects, 10_000);
+    }
+
+    #[test]
+    fn rounds_objects() {
+        assert_eq!(objects(0), 0);
+        assert_eq!(objects(OBJECT_UNIT / 2 - 1), 0);
+        assert_eq!(objects(OBJECT_UNIT / 2), 1);
+        assert_eq!(objects(3 * OBJECT_UNIT), 3);
+    }
+}
@@ -0,0 +1,153 @@
+use crate::profiler::unrecorded;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::RwLock;
+use std::task::{Context, Poll};
+
+/// Tags are interned once, and allocations refer to them by their id. The table is never shrunk.
+#[derive(Default)]
+struct Tags {
+    ids: HashMap<String, u32>,
+    names: Vec<String>,
+}
+
+lazy_static::lazy_static! {
+    static ref TAGS: RwLock<Tags> = RwLock::new(Tags::default());
+}
+
+thread_local! {
+    static TAG: Cell<u32> = const { Cell::new(0) };
+}
+
+/// Gives the id of `tag`, from 1. Known tags are only looked up under the read lock. New ones are
+/// added unrecorded, so the lock is never held while an event is sent.
+fn intern(tag: &str) -> u32 {
+    if let Some(id) = TAGS.read().unwrap().ids.get(tag) {
+        return *id;
+    }
+
+    unrecorded(|| {
+        let mut tags = TAGS.write().unwrap();
+        if let Some(id) = tags.ids.get(tag) {
+            return *id;
+        }
+
+        tags.names.push(tag.to_owned());
+        let id = tags.names.len() as u32;
+        tags.ids.insert(tag.to_owned(), id);
+
+        id
+    })
+}
+
+/// The id of the innermost tag of the current thread, or 0 outside of any scope.
+pub(crate) fn current() -> u32 {
+    TAG.try_with(|tag| tag.get()).unwrap_or(0)
+}
+
+/// Gives the names of all tags, keyed by their id.
+pub(crate) fn names() -> HashMap<u32, String> {
+    let tags = TAGS.read().unwrap();
+    tags.names
+        .iter()
+        .enumerate()
+        .map(|(index, name)| (index as u32 + 1, name.clone()))
+        .collect()
+}
+
+/// Tag is an interned scope tag. Scopes entered with a `Tag` skip looking up its name, which is
+/// worth it on hot paths.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Tag(u32);
+
+impl Tag {
+    pub fn new(name: &str) -> Tag {
+        Tag(intern(name))
+    }
+}
+
+impl From<&str> for Tag {
+    fn from(name: &str) -> Tag {
+        Tag::new(name)
+    }
+}
+
+impl From<&String> for Tag {
+    fn from(name: &String) -> Tag {
+        Tag::new(name)
+    }
+}
+
+/// ScopeGuard restores the tag of the enclosing scope when dropped. Guards must be dropped in the
+/// reverse order of their creation, which is what happens when they are kept on the stack.
+#[must_use = "the scope ends when the guard is dropped"]
+pub struct ScopeGuard {
+    previous: u32,
+
+    // the tag belongs to the current thread
+    _marker: PhantomData<*const ()>,
+}
+
+impl Drop for ScopeGuard {
+    fn drop(&mut self) {
+        let _ = TAG.try_with(|tag| tag.set(self.previous));
+    }
+}
+
+/// Tags the allocations made by the current thread with `tag` until the guard is dropped, so
+/// reports can be aggregated by logical operation, like `request:upload`, instead of by stack.
+/// Scopes nest, and an allocation is tagged by the innermost one.
+pub fn scope<T>(tag: T) -> ScopeGuard
+where
+    T: Into<Tag>,
+{
+    enter(tag.into())
+}
+
+fn enter(Tag(id): Tag) -> ScopeGuard {
+    let previous = TAG.with(|tag| tag.replace(id));
+
+    ScopeGuard {
+        previous,
+        _marker: PhantomData,
+    }
+}
+
+/// Instrumented tags the allocations made while its future is polled, so they are attributed to
+/// the logical task instead of the thread of the executor which happens to poll it. The live bytes
+/// of a task are found under its tag, in `Report::tags`.
+pub struct Instrumented<F> {
+    inner: F,
+    tag: Tag,
+}
+
+impl<F: Future> Instrumented<F> {
+    pub fn new<T>(inner: F, tag: T) -> Self
+    where
+        T: Into<Tag>,
+    {
+        Instrumented {
+            inner,
+            tag: tag.into(),
+        }
+    }
+
+    pub fn into_inner(self) -> F {
+        self.inner
+    }
+}
+
+impl<F: Future> Future for Instrumented<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<F::Output> {
+        // the future is never moved out of the pinned wrapper
+        let this = unsafe { self.get_unchecked_mut() };
+        let _guard = enter(this.tag);
+
+        unsafe { Pin::new_unchecked(&mut this.inner) }.poll(cx)
+    }
+}
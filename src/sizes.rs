@@ -0,0 +1,176 @@
+use crate::sampler::{self, Objects};
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::ops::AddAssign;
+
+/// SizeClasses selects the buckets of a `SizeHistogram`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SizeClasses {
+    /// Buckets up to 8, 16, 32, 64... bytes.
+    PowerOfTwo,
+
+    /// The size classes of jemalloc on 64-bit platforms: 8, 16, then 4 classes in every doubling,
+    /// e.g. 80, 96, 112 and 128. tcmalloc and mimalloc use similar classes.
+    Jemalloc,
+}
+
+/// Rounds `size` up to its jemalloc size class. Sizes above the largest class give `usize::MAX`.
+fn size_class(size: usize) -> usize {
+    if size <= 8 {
+        return 8;
+    }
+    if size <= 16 {
+        return 16;
+    }
+
+    // 2^lg < size <= 2^(lg + 1), which is split into 4 classes, and never less than 16 bytes apart
+    let lg = usize::BITS - 1 - (size - 1).leading_zeros();
+    let step = (1usize << lg.saturating_sub(2)).max(16);
+    ((size - 1) | (step - 1)).saturating_add(1)
+}
+
+/// SizeHistogram counts allocations by their requested size. Reallocations are counted with their
+/// new size. The counts are kept by jemalloc size class, every one of which falls into a single
+/// power of two bucket.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SizeHistogram {
+    classes: BTreeMap<usize, usize>,
+}
+
+impl SizeHistogram {
+    pub(crate) fn add(&mut self, size: usize, objects: usize) {
+        *self.classes.entry(size_class(size)).or_default() += objects;
+    }
+
+    /// Adds `objects` allocations to the class with the upper bound `class`, as given by `buckets`
+    /// with `SizeClasses::Jemalloc`.
+    pub(crate) fn add_class(&mut self, class: usize, objects: usize) {
+        *self.classes.entry(class).or_default() += objects;
+    }
+
+    /// Gives the number of allocations in every non-empty bucket, keyed by the upper bound of the
+    /// bucket in bytes, from the smallest.
+    pub fn buckets(&self, classes: SizeClasses) -> Vec<(usize, usize)> {
+        match classes {
+            SizeClasses::Jemalloc => self
+                .classes
+                .iter()
+                .map(|(size, objects)| (*size, *objects))
+                .collect(),
+            SizeClasses::PowerOfTwo => {
+                let mut buckets: Vec<(usize, usize)> = Vec::new();
+                for (size, objects) in self.classes.iter() {
+                    let bucket = size.checked_next_power_of_two().unwrap_or(usize::MAX);
+                    match buckets.last_mut() {
+                        Some((last, count)) if *last == bucket => *count += objects,
+                        _ => buckets.push((bucket, *objects)),
+                    }
+                }
+
+                buckets
+            }
+        }
+    }
+
+    /// Number of allocations counted.
+    pub fn objects(&self) -> usize {
+        self.classes.values().sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.classes.is_empty()
+    }
+
+    /// Writes the buckets as `[<=8: 3, <=16: 5]`.
+    pub(crate) fn write_buckets(&self, f: &mut Formatter, classes: SizeClasses) -> std::fmt::Result {
+        write!(f, "[")?;
+        for (index, (size, objects)) in self.buckets(classes).into_iter().enumerate() {
+            if index > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "<={}: {}", size, objects)?;
+        }
+        write!(f, "]")
+    }
+}
+
+impl Objects for SizeHistogram {
+    fn round_objects(mut self) -> Self {
+        self.classes
+            .values_mut()
+            .for_each(|objects| *objects = sampler::objects(*objects));
+        self.classes.retain(|_, objects| *objects > 0);
+        self
+    }
+}
+
+impl AddAssign for SizeHistogram {
+    fn add_assign(&mut self, other: SizeHistogram) {
+        for (size, objects) in other.classes {
+            *self.classes.entry(size).or_default() += objects;
+        }
+    }
+}
+
+impl Display for SizeHistogram {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "sizes: ")?;
+        self.write_buckets(f, SizeClasses::PowerOfTwo)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn small_classes() {
+        assert_eq!(size_class(0), 8);
+        assert_eq!(size_class(1), 8);
+        assert_eq!(size_class(8), 8);
+        assert_eq!(size_class(9), 16);
+        assert_eq!(size_class(16), 16);
+        assert_eq!(size_class(17), 32);
+    }
+
+    #[test]
+    fn classes_around_powers_of_two() {
+        assert_eq!(size_class(32), 32);
+        assert_eq!(size_class(33), 48);
+        assert_eq!(size_class(64), 64);
+        assert_eq!(size_class(65), 80);
+        assert_eq!(size_class(128), 128);
+        assert_eq!(size_class(129), 160);
+        assert_eq!(size_class(1024), 1024);
+        assert_eq!(size_class(1025), 1280);
+        assert_eq!(size_class(1 << 20), 1 << 20);
+        assert_eq!(size_class((1 << 20) + 1), (1 << 20) + (1 << 18));
+    }
+
+    #[test]
+    fn largest_classes() {
+        let top = 1usize << (usize::BITS - 1);
+        assert_eq!(size_class(top), top);
+        assert_eq!(size_class(top - 1), top);
+        assert_eq!(size_class(top + 1), top + (top >> 2));
+        assert_eq!(size_class(usize::MAX), usize::MAX);
+    }
+
+    #[test]
+    fn power_of_two_buckets() {
+        let mut histogram = SizeHistogram::default();
+        histogram.add(1, 1);
+        histogram.add(33, 2);
+        histogram.add(64, 3);
+        histogram.add(usize::MAX, 1);
+
+        assert_eq!(
+            histogram.buckets(SizeClasses::Jemalloc),
+            vec![(8, 1), (48, 2), (64, 3), (usize::MAX, 1)]
+        );
+        assert_eq!(
+            histogram.buckets(SizeClasses::PowerOfTwo),
+            vec![(8, 1), (64, 5), (usize::MAX, 1)]
+        );
+    }
+}
@@ -0,0 +1,78 @@
+use crate::frame::UnresolvedFrames;
+use std::collections::HashMap;
+use std::mem::size_of;
+
+/// StackTable interns the stacks seen by the collector. Every distinct stack is stored once, and
+/// referred to by a `u32` id which is its index in the table. Threads only know the hash of a stack,
+/// which is translated into the id when the event is handled.
+#[derive(Default)]
+pub struct StackTable {
+    stacks: Vec<UnresolvedFrames>,
+    ids: HashMap<u64, u32>,
+    frames_bytes: usize,
+}
+
+impl StackTable {
+    /// Returns the id of the stack with `hash`, adding `frames` if it is new. Gives `None` if
+    /// another stack has the same hash.
+    pub fn intern(&mut self, hash: u64, frames: UnresolvedFrames) -> Option<u32> {
+        if let Some(id) = self.get(hash) {
+            return (self.stacks[id as usize] == frames).then_some(id);
+        }
+
+        self.frames_bytes += frames.frames.capacity() * size_of::<u64>();
+        self.stacks.push(frames);
+        let id = (self.stacks.len() - 1) as u32;
+        self.ids.insert(hash, id);
+
+        Some(id)
+    }
+
+    pub fn get(&self, hash: u64) -> Option<u32> {
+        self.ids.get(&hash).copied()
+    }
+
+    pub fn frames(&self, id: u32) -> &UnresolvedFrames {
+        &self.stacks[id as usize]
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &UnresolvedFrames> {
+        self.stacks.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.stacks.len()
+    }
+
+    /// Estimated bytes held by the table.
+    pub fn heap_bytes(&self) -> usize {
+        self.stacks.capacity() * size_of::<UnresolvedFrames>()
+            + self.ids.capacity() * (size_of::<(u64, u32)>() + 1)
+            + self.frames_bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intern_stacks() {
+        let mut table = StackTable::default();
+        assert_eq!(table.intern(1, UnresolvedFrames::new(&[1, 2])), Some(0));
+        assert_eq!(table.intern(2, UnresolvedFrames::new(&[3])), Some(1));
+        assert_eq!(table.intern(1, UnresolvedFrames::new(&[1, 2])), Some(0));
+        assert_eq!(table.get(2), Some(1));
+        assert_eq!(table.get(3), None);
+        assert_eq!(table.len(), 2);
+    }
+
+    #[test]
+    fn detect_collisions() {
+        let mut table = StackTable::default();
+        assert_eq!(table.intern(1, UnresolvedFrames::new(&[1, 2])), Some(0));
+        assert_eq!(table.intern(1, UnresolvedFrames::new(&[1, 3])), None);
+        assert_eq!(table.frames(0).frames, vec![1, 2]);
+        assert_eq!(table.len(), 1);
+    }
+}
@@ -0,0 +1,97 @@
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Stats describe the overhead of the profiler itself, so the numbers of a report can be judged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Events handled by the collector.
+    pub events: u64,
+
+    /// Events which did not match the records of the collector, e.g. the free of an unknown
+    /// address. They are left out of the reports.
+    pub dropped_events: u64,
+
+    /// Events sent to the collector and not handled yet.
+    pub queue_depth: usize,
+
+    /// Live allocations tracked by the collector, which is the size of its address map.
+    pub tracked_allocations: usize,
+
+    /// Distinct stacks in the stack table.
+    pub stacks: usize,
+
+    /// Estimated bytes held by the collector and the event buffers.
+    pub collector_bytes: usize,
+
+    /// Time spent unwinding stacks on the allocating threads.
+    pub unwind_time: Duration,
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "events: {} ({} dropped, {} queued), allocations: {}, stacks: {}, collector: {} bytes, unwinding: {:?}",
+            self.events,
+            self.dropped_events,
+            self.queue_depth,
+            self.tracked_allocations,
+            self.stacks,
+            self.collector_bytes,
+            self.unwind_time
+        )
+    }
+}
+
+/// Counters is updated by the collector after every batch of events, and by the allocating threads
+/// for the time they spend unwinding, so the stats can be read without waiting for the collector.
+pub(crate) struct Counters {
+    events: AtomicU64,
+    dropped_events: AtomicU64,
+    pending: AtomicUsize,
+    tracked_allocations: AtomicUsize,
+    stacks: AtomicUsize,
+    collector_bytes: AtomicUsize,
+    unwind_nanos: AtomicU64,
+}
+
+pub(crate) static COUNTERS: Counters = Counters {
+    events: AtomicU64::new(0),
+    dropped_events: AtomicU64::new(0),
+    pending: AtomicUsize::new(0),
+    tracked_allocations: AtomicUsize::new(0),
+    stacks: AtomicUsize::new(0),
+    collector_bytes: AtomicUsize::new(0),
+    unwind_nanos: AtomicU64::new(0),
+};
+
+impl Counters {
+    /// Publishes the state of the collector. `pending` events have been drained from the buffers
+    /// but not handled.
+    pub fn publish(&self, stats: &Stats, pending: usize) {
+        self.events.store(stats.events, Ordering::Relaxed);
+        self.dropped_events.store(stats.dropped_events, Ordering::Relaxed);
+        self.pending.store(pending, Ordering::Relaxed);
+        self.tracked_allocations.store(stats.tracked_allocations, Ordering::Relaxed);
+        self.stacks.store(stats.stacks, Ordering::Relaxed);
+        self.collector_bytes.store(stats.collector_bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_unwind_time(&self, time: Duration) {
+        self.unwind_nanos.fetch_add(time.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// The last published stats, with `queued` events still waiting in the buffers.
+    pub fn stats(&self, queued: usize) -> Stats {
+        Stats {
+            events: self.events.load(Ordering::Relaxed),
+            dropped_events: self.dropped_events.load(Ordering::Relaxed),
+            queue_depth: queued + self.pending.load(Ordering::Relaxed),
+            tracked_allocations: self.tracked_allocations.load(Ordering::Relaxed),
+            stacks: self.stacks.load(Ordering::Relaxed),
+            collector_bytes: self.collector_bytes.load(Ordering::Relaxed),
+            unwind_time: Duration::from_nanos(self.unwind_nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
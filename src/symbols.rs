@@ -0,0 +1,75 @@
+use crate::frame::{resolve_addr, Frames, Symbol, UnresolvedFrames};
+use std::collections::HashMap;
+use std::mem::size_of;
+
+/// SymbolCache keeps the symbols of every resolved address, so an address is only resolved once
+/// however many stacks and reports it appears in.
+#[derive(Default)]
+pub struct SymbolCache {
+    symbols: HashMap<u64, Vec<Symbol>>,
+
+    /// Whether the frames of the profiler are trimmed from resolved stacks, which are then cut to
+    /// `max_depth` frames.
+    trim_frames: bool,
+    max_depth: usize,
+}
+
+impl SymbolCache {
+    pub fn new(trim_frames: bool, max_depth: usize) -> Self {
+        SymbolCache {
+            trim_frames,
+            max_depth,
+            ..Default::default()
+        }
+    }
+
+    fn symbols(&mut self, addr: u64) -> &Vec<Symbol> {
+        self.symbols
+            .entry(addr)
+            .or_insert_with(|| resolve_addr(addr))
+    }
+
+    pub fn resolve(&mut self, frames: &UnresolvedFrames) -> Frames {
+        let mut resolved = Frames {
+            frames: frames
+                .frames
+                .iter()
+                .map(|addr| self.symbols(*addr).clone())
+                .collect(),
+            addresses: frames.frames.clone(),
+        };
+        if self.trim_frames {
+            resolved.trim_profiler_frames(self.max_depth);
+        }
+
+        resolved
+    }
+
+    /// Resolves the addresses of `frames` ahead of the next report.
+    pub fn warm(&mut self, frames: &UnresolvedFrames) {
+        frames.frames.iter().for_each(|addr| {
+            self.symbols(*addr);
+        });
+    }
+
+    pub fn clear(&mut self) {
+        self.symbols = HashMap::new();
+    }
+
+    /// Estimated bytes held by the cache.
+    pub fn heap_bytes(&self) -> usize {
+        let symbols: usize = self
+            .symbols
+            .values()
+            .map(|symbols| {
+                symbols.capacity() * size_of::<Symbol>()
+                    + symbols
+                        .iter()
+                        .map(|symbol| symbol.name.as_ref().map_or(0, Vec::len))
+                        .sum::<usize>()
+            })
+            .sum();
+
+        self.symbols.capacity() * (size_of::<(u64, Vec<Symbol>)>() + 1) + symbols
+    }
+}
@@ -0,0 +1,158 @@
+/// Unwinder selects how the stacks of allocations are captured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unwinder {
+    /// DWARF based unwinding through `backtrace::trace`. It works with any binary, but it is slow
+    /// and could take locks inside the allocator.
+    Backtrace,
+
+    /// Walks the chain of frame pointers, which is much cheaper. Every frame must keep its frame
+    /// pointer, e.g. by building with `-C force-frame-pointers=yes`. It is supported on x86_64
+    /// and aarch64 Linux, and falls back to `Backtrace` when the chain is missing.
+    #[cfg(feature = "frame-pointer")]
+    FramePointer,
+}
+
+/// A shorter chain of frame pointers means some frames do not keep them, as the profiler alone
+/// has more frames than this.
+#[cfg(feature = "frame-pointer")]
+const MIN_FRAME_POINTER_DEPTH: usize = 4;
+
+/// Fills `frames` with the instruction addresses of the current stack, the innermost first.
+/// Returns the number of frames and whether the stack is deeper than `frames`.
+pub(crate) fn trace(unwinder: Unwinder, frames: &mut [u64]) -> (usize, bool) {
+    #[cfg(feature = "frame-pointer")]
+    {
+        if unwinder == Unwinder::FramePointer {
+            let (depth, truncated) = frame_pointer::trace(frames);
+            if depth >= MIN_FRAME_POINTER_DEPTH.min(frames.len()) {
+                return (depth, truncated);
+            }
+        }
+    }
+    #[cfg(not(feature = "frame-pointer"))]
+    let _ = unwinder;
+
+    trace_backtrace(frames)
+}
+
+fn trace_backtrace(frames: &mut [u64]) -> (usize, bool) {
+    let mut depth = 0;
+    let mut truncated = false;
+
+    backtrace::trace(|frame| {
+        if depth < frames.len() {
+            frames[depth] = frame.ip() as u64;
+            depth += 1;
+            true
+        } else {
+            truncated = true;
+            false
+        }
+    });
+
+    (depth, truncated)
+}
+
+#[cfg(all(
+    feature = "frame-pointer",
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod frame_pointer {
+    use crate::profiler::unrecorded;
+    use std::cell::Cell;
+    use std::mem::MaybeUninit;
+
+    thread_local! {
+        /// The stack of the current thread, or `(0, 0)` before it is known.
+        static STACK: Cell<(u64, u64)> = const { Cell::new((0, 0)) };
+    }
+
+    fn stack_bounds() -> Option<(u64, u64)> {
+        let bounds = STACK.try_with(|stack| {
+            if stack.get() == (0, 0) {
+                // glibc reads `/proc/self/maps` for the main thread, which allocates
+                if let Some(bounds) = unrecorded(|| unsafe { current_stack() }) {
+                    stack.set(bounds);
+                }
+            }
+            stack.get()
+        });
+
+        bounds.ok().filter(|bounds| *bounds != (0, 0))
+    }
+
+    unsafe fn current_stack() -> Option<(u64, u64)> {
+        let mut attr = MaybeUninit::<libc::pthread_attr_t>::uninit();
+        if libc::pthread_getattr_np(libc::pthread_self(), attr.as_mut_ptr()) != 0 {
+            return None;
+        }
+
+        let mut addr = std::ptr::null_mut();
+        let mut size = 0;
+        let result = libc::pthread_attr_getstack(attr.as_ptr(), &mut addr, &mut size);
+        libc::pthread_attr_destroy(attr.as_mut_ptr());
+
+        if result == 0 {
+            Some((addr as u64, addr as u64 + size as u64))
+        } else {
+            None
+        }
+    }
+
+    #[inline(always)]
+    fn frame_pointer() -> u64 {
+        let fp: u64;
+        unsafe {
+            #[cfg(target_arch = "x86_64")]
+            std::arch::asm!("mov {}, rbp", out(reg) fp, options(nomem, nostack, preserves_flags));
+            #[cfg(target_arch = "aarch64")]
+            std::arch::asm!("mov {}, x29", out(reg) fp, options(nomem, nostack, preserves_flags));
+        }
+        fp
+    }
+
+    /// Every frame record holds the frame pointer of the caller followed by the return address.
+    /// The walk stops at the first record which is not inside the stack of the thread, or which
+    /// does not lead to an outer frame, so a missing frame pointer ends the stack early instead of
+    /// reading arbitrary memory.
+    #[inline(never)]
+    pub fn trace(frames: &mut [u64]) -> (usize, bool) {
+        let (low, high) = match stack_bounds() {
+            Some(bounds) => bounds,
+            None => return (0, false),
+        };
+
+        let mut fp = frame_pointer();
+        let mut depth = 0;
+        while fp >= low && fp + 16 <= high && fp & 7 == 0 {
+            let (next, ip) = unsafe { (*(fp as *const u64), *((fp + 8) as *const u64)) };
+            if ip == 0 {
+                break;
+            }
+            if depth == frames.len() {
+                return (depth, true);
+            }
+
+            frames[depth] = ip;
+            depth += 1;
+
+            if next <= fp {
+                break;
+            }
+            fp = next;
+        }
+
+        (depth, false)
+    }
+}
+
+#[cfg(all(
+    feature = "frame-pointer",
+    not(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))
+))]
+mod frame_pointer {
+    pub fn trace(_frames: &mut [u64]) -> (usize, bool) {
+        (0, false)
+    }
+}
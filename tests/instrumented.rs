@@ -0,0 +1,78 @@
+use cogito::{scope, AllocRecorder, Instrumented, Tag};
+use std::alloc::System;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+
+#[global_allocator]
+static ALLOC: AllocRecorder<System> = AllocRecorder::new(System);
+
+type Task = Pin<Box<dyn Future<Output = ()>>>;
+
+/// Polls the tasks in turn until all of them are ready.
+fn run(tasks: Vec<Task>) {
+    let mut cx = Context::from_waker(Waker::noop());
+
+    let mut queue: VecDeque<Task> = tasks.into_iter().collect();
+    while let Some(mut task) = queue.pop_front() {
+        if task.as_mut().poll(&mut cx).is_pending() {
+            queue.push_back(task);
+        }
+    }
+}
+
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<()> {
+        if self.0 {
+            Poll::Ready(())
+        } else {
+            self.0 = true;
+            Poll::Pending
+        }
+    }
+}
+
+async fn buffer(buffers: Rc<RefCell<Vec<Vec<u8>>>>, size: usize, count: usize) {
+    for _ in 0..count {
+        buffers.borrow_mut().push(vec![1; size]);
+        YieldNow(false).await;
+    }
+}
+
+#[test]
+fn tasks_are_attributed_by_tag() {
+    ALLOC.init_collector().unwrap();
+
+    // allocated up front, so the tasks allocate nothing but their buffers
+    let buffers = Rc::new(RefCell::new(Vec::with_capacity(21)));
+    let large = Tag::new("task:large");
+    run(vec![
+        Box::pin(Instrumented::new(buffer(buffers.clone(), 1000, 10), "task:small")),
+        Box::pin(Instrumented::new(buffer(buffers.clone(), 3000, 10), large)),
+    ]);
+
+    {
+        let _scope = scope(large);
+        buffers.borrow_mut().push(vec![1; 3000]);
+    }
+
+    {
+        let report = ALLOC.report().unwrap();
+        let tags = report.as_ref().tag_stats();
+
+        assert_eq!(tags.len(), 2);
+        assert_eq!(tags["task:small"].live_bytes, 10 * 1000);
+        assert_eq!(tags["task:small"].live_objects, 10);
+        assert_eq!(tags["task:large"].live_bytes, 11 * 3000);
+        assert_eq!(tags["task:large"].live_objects, 11);
+    }
+
+    drop(buffers);
+}
@@ -0,0 +1,53 @@
+use cogito::AllocRecorder;
+use std::alloc::{alloc, dealloc, Layout, System};
+use std::hint::black_box;
+
+#[global_allocator]
+static ALLOC: AllocRecorder<System> = AllocRecorder::new(System).max_depth(4);
+
+#[inline(never)]
+fn caller(layout: Layout) -> *mut u8 {
+    // not a tail call, so the frame stays on the stack
+    black_box(unsafe { alloc(layout) })
+}
+
+#[inline(never)]
+fn nested(depth: usize, layout: Layout) -> *mut u8 {
+    let ptr = if depth == 0 {
+        caller(layout)
+    } else {
+        nested(depth - 1, layout)
+    };
+
+    black_box(ptr)
+}
+
+#[test]
+fn profiler_frames_do_not_count() {
+    ALLOC.init_collector().unwrap();
+
+    let layout = Layout::from_size_align(12345, 8).unwrap();
+    let ptr = nested(8, layout);
+
+    {
+        let report = ALLOC.report().unwrap();
+        let (frames, _) = report
+            .as_ref()
+            .live
+            .iter()
+            .find(|(_, stats)| stats.live_bytes == layout.size())
+            .unwrap();
+
+        let names: Vec<String> = frames
+            .frames
+            .iter()
+            .map(|frame| frame.last().unwrap().name())
+            .collect();
+        assert_eq!(names.len(), 4, "{}", frames);
+        assert!(names[0].contains("::caller"), "{}", frames);
+        assert!(names[1].contains("::nested") && names[2].contains("::nested"), "{}", frames);
+        assert_eq!(names[3], "[truncated]");
+    }
+
+    unsafe { dealloc(ptr, layout) };
+}
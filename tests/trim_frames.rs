@@ -0,0 +1,33 @@
+use cogito::AllocRecorder;
+use std::alloc::{alloc, dealloc, Layout, System};
+
+#[global_allocator]
+static ALLOC: AllocRecorder<System> = AllocRecorder::new(System);
+
+#[inline(never)]
+fn caller(layout: Layout) -> *mut u8 {
+    unsafe { alloc(layout) }
+}
+
+#[test]
+fn stacks_start_from_the_caller() {
+    ALLOC.init_collector().unwrap();
+
+    let layout = Layout::from_size_align(12345, 8).unwrap();
+    let ptr = caller(layout);
+
+    {
+        let report = ALLOC.report().unwrap();
+        let (frames, _) = report
+            .as_ref()
+            .live
+            .iter()
+            .find(|(_, stats)| stats.live_bytes == layout.size())
+            .unwrap();
+
+        let first = frames.frames[0].last().unwrap().name();
+        assert!(first.contains("trim_frames") && first.contains("::caller"), "{}", frames);
+    }
+
+    unsafe { dealloc(ptr, layout) };
+}